@@ -1,4 +1,18 @@
+use std::{env, path::PathBuf};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("api/protos/proxy.proto")?;
+    // 描述文件用于 gRPC 服务反射
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("proxy_descriptor.bin"))
+        // http 接口使用 proto3 的 json 字段名，例如 roomId
+        .type_attribute(
+            "proxy.Message",
+            "#[derive(serde::Serialize, serde::Deserialize)] #[serde(default, rename_all = \"camelCase\")]",
+        )
+        .compile_protos(
+            &["api/protos/proxy.proto", "api/protos/grpc/reflection/v1alpha/reflection.proto"],
+            &["api/protos"],
+        )?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,2 +1,4 @@
-cp config.json ./target/release/config.json
-cargo build --release
\ No newline at end of file
+cargo build --release
+# 不复制 config.json（其中可能有密钥），部署时参考 config.example.toml 编写配置，
+# 密钥用 secrets.json 或 WECHAT_BOT_* 环境变量在部署机上单独提供
+cp config.example.toml ./target/release/config.example.toml
@@ -0,0 +1,195 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{error, info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::dispatch::Dispatcher;
+use crate::proxy::{Message, MessageResp, RespCode};
+
+/// 其它聊天网络收到的一条消息
+#[derive(Debug, Clone)]
+pub struct Incoming {
+    /// 映射后交给 Dispatcher 的消息，room_id 为平台的会话 id
+    pub message: Message,
+    /// 平台的消息 id，回复时引用
+    pub reply_to: String,
+}
+
+/// 与平台无关的回复，由各个适配器决定如何展示
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// 不回复
+    None,
+    /// 正常回复
+    Text(String),
+    /// 限流等提示，不需要引起注意
+    Notice(String),
+    /// 指令出错
+    Error(String),
+}
+
+impl From<MessageResp> for Reply {
+    fn from(resp: MessageResp) -> Self {
+        if resp.response.is_empty() {
+            return Reply::None;
+        }
+        match resp.code() {
+            RespCode::Ignore => Reply::None,
+            RespCode::Ok => Reply::Text(resp.response),
+            RespCode::RateLimited => Reply::Notice(resp.response),
+            RespCode::Coruption | RespCode::BadRequest | RespCode::Unavailable => Reply::Error(resp.response),
+        }
+    }
+}
+
+/// 聊天网络的接入，把平台的事件转换成 Message，再把 Reply 发回平台
+#[async_trait::async_trait]
+pub trait ChatAdapter: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// 等待并返回新消息，长轮询的适配器在超时后返回空列表
+    async fn poll(&self) -> Result<Vec<Incoming>>;
+
+    async fn send(&self, incoming: &Incoming, reply: &Reply) -> Result<()>;
+}
+
+async fn handle(adapter: &dyn ChatAdapter, dispatcher: &Dispatcher, incoming: Incoming) {
+    let reply = Reply::from(dispatcher.handle(incoming.message.clone()).await);
+    if reply == Reply::None {
+        return;
+    }
+    if let Err(e) = adapter.send(&incoming, &reply).await {
+        error!("{} failed to send reply, err: {:?}", adapter.name(), e);
+    }
+}
+
+/// 持续拉取消息并交给 Dispatcher，每条消息单独处理；stop 变为 true 后不再拉取，
+/// 等处理中的消息回复完（最多 server.request_timeout 秒）再退出
+pub async fn run(adapter: Arc<dyn ChatAdapter>, dispatcher: Arc<Dispatcher>, mut stop: watch::Receiver<bool>) {
+    info!("adapter {} started", adapter.name());
+    let mut tasks = JoinSet::new();
+    loop {
+        while tasks.try_join_next().is_some() {}
+        let polled = tokio::select! {
+            polled = adapter.poll() => polled,
+            _ = stop.wait_for(|s| *s) => break,
+        };
+        match polled {
+            Ok(messages) => {
+                for incoming in messages {
+                    let (adapter, dispatcher) = (adapter.clone(), dispatcher.clone());
+                    tasks.spawn(async move { handle(adapter.as_ref(), &dispatcher, incoming).await });
+                }
+            }
+            Err(e) => {
+                error!("adapter {} failed to poll, err: {:?}", adapter.name(), e);
+                // 网络出错时不要立刻重试
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                    _ = stop.wait_for(|s| *s) => break,
+                }
+            }
+        }
+    }
+    let seconds = dispatcher.state().config().await.server.request_timeout;
+    let drain = async { while tasks.join_next().await.is_some() {} };
+    if tokio::time::timeout(Duration::from_secs(seconds), drain).await.is_err() {
+        warn!("adapter {} dropped {} unfinished messages", adapter.name(), tasks.len());
+        tasks.abort_all();
+    }
+    info!("adapter {} stopped", adapter.name());
+}
+
+#[test]
+fn test_reply() {
+    let resp = |code: RespCode, response: &str| MessageResp {
+        code: code.into(),
+        response: response.to_string(),
+    };
+    assert_eq!(Reply::from(resp(RespCode::Ok, "hi")), Reply::Text(String::from("hi")));
+    assert_eq!(Reply::from(resp(RespCode::Ok, "")), Reply::None);
+    assert_eq!(Reply::from(resp(RespCode::Ignore, "x")), Reply::None);
+    assert_eq!(Reply::from(resp(RespCode::RateLimited, "慢点")), Reply::Notice(String::from("慢点")));
+    assert_eq!(Reply::from(resp(RespCode::RateLimited, "")), Reply::None);
+    assert_eq!(Reply::from(resp(RespCode::Unavailable, "哦豁")), Reply::Error(String::from("哦豁")));
+}
+
+#[tokio::test]
+async fn test_run_drains_on_stop() {
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::config::Config;
+    use crate::handler::{Context, Handler, HandlerMgr};
+    use crate::state::BotState;
+
+    // 只返回一条消息，之后一直等待
+    #[derive(Default)]
+    struct Once {
+        polled: AtomicBool,
+        sent: Mutex<Vec<Reply>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ChatAdapter for Once {
+        fn name(&self) -> &str {
+            "once"
+        }
+
+        async fn poll(&self) -> Result<Vec<Incoming>> {
+            if self.polled.swap(true, Ordering::SeqCst) {
+                std::future::pending::<()>().await;
+            }
+            let message = Message {
+                is_room: false,
+                is_memtioned: false,
+                room_id: String::new(),
+                content: String::from("/slow"),
+                sender_id: String::from("friend"),
+                message_id: String::new(),
+            };
+            Ok(vec![Incoming { message, reply_to: String::new() }])
+        }
+
+        async fn send(&self, _incoming: &Incoming, reply: &Reply) -> Result<()> {
+            self.sent.lock().unwrap().push(reply.clone());
+            Ok(())
+        }
+    }
+
+    struct Slow;
+
+    #[async_trait::async_trait]
+    impl Handler for Slow {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        fn matches(&self, msg: &str) -> bool {
+            msg == "slow"
+        }
+
+        async fn on_message(&self, _ctx: &Context, _msg: &str) -> Result<String> {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(String::from("done"))
+        }
+    }
+
+    let config = Config::builder().set("users", r#"[{"id": "friend"}]"#).build().unwrap();
+    let mut handlers = HandlerMgr::new();
+    handlers.register_handler(Slow);
+    let dispatcher = Arc::new(Dispatcher::new(Arc::new(BotState::new(config).unwrap()), handlers, 1));
+    let once = Arc::new(Once::default());
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let task = tokio::spawn(run(once.clone(), dispatcher, stop_rx));
+    while !once.polled.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // 收到退出信号时消息还在处理，run 要等它回复完再返回
+    stop_tx.send(true).unwrap();
+    task.await.unwrap();
+    assert_eq!(*once.sent.lock().unwrap(), vec![Reply::Text(String::from("done"))]);
+}
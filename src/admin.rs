@@ -0,0 +1,397 @@
+use anyhow::Result;
+use chrono::{Local, NaiveDate, TimeDelta};
+use log::info;
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::handler::{Context, HandlerMgr};
+use crate::state::Breaker;
+use crate::usage::{day_range, month_range};
+use crate::webhook::{PushMessage, Webhook};
+
+/// 管理指令前缀，只有 config.admins 中的用户可以使用
+pub const PROMPT: &str = "admin";
+/// /usage 是 /admin usage 的简写
+pub const USAGE_PROMPT: &str = "usage";
+
+/// 识别管理指令，返回 /admin 之后的参数
+pub fn strip_prompt(msg: &str) -> Option<&str> {
+    let is_word = |rest: &str| rest.is_empty() || rest.starts_with(char::is_whitespace);
+    if let Some(rest) = msg.strip_prefix(PROMPT).filter(|r| is_word(r)) {
+        return Some(rest.trim());
+    }
+    msg.strip_prefix(USAGE_PROMPT).filter(|r| is_word(r)).map(|_| msg.trim())
+}
+
+/// 只在启动时读取的配置，/admin reload 之后要重启才生效；指令（scripts、plugins）在启动时注册，也不会重新加载
+pub const RESTART_REQUIRED: &[&str] = &[
+    "server.listen",
+    "server.max_in_flight",
+    "server.tls",
+    "server.auth_token",
+    "http",
+    "log",
+    "telegram",
+    "matrix",
+    "scripts",
+    "scripts_file",
+    "plugins",
+    "storage",
+    "record",
+    "upstream.connect_timeout",
+    "upstream.proxy",
+    "upstream.proxy_providers",
+    "upstream.user_agent",
+];
+
+// 两份配置中有变化、但要重启才生效的字段
+fn restart_required(old: &Config, new: &Config) -> Result<Vec<&'static str>> {
+    let (old, new) = (serde_json::to_value(old)?, serde_json::to_value(new)?);
+    Ok(RESTART_REQUIRED
+        .iter()
+        .copied()
+        .filter(|key| {
+            let pointer = format!("/{}", key.replace('.', "/"));
+            old.pointer(&pointer) != new.pointer(&pointer)
+        })
+        .collect())
+}
+
+const USAGE: &str = r#"管理指令：
+/admin status - 运行状态
+/admin health [reset <上游>] - 上游健康和熔断状态
+/admin enable <指令> [会话id] - 开启指令
+/admin disable <指令> [会话id] - 关闭指令
+/admin model <模型|reset> [会话id] - 切换模型
+/admin preset <提示词|reset> - 切换本会话提示词
+/admin mute <分钟> [会话id] - 禁言
+/admin unmute [会话id] - 解除禁言
+/admin reload - 重新加载配置（指令、插件、监听地址等需要重启）
+/admin limits [reset] - 限流计数
+/admin push <会话id> <内容> - 通过 webhook 推送消息
+/usage [day|month] [日期] - 大模型用量"#;
+
+pub async fn on_admin(ctx: &Context, mgr: &HandlerMgr, args: &str) -> Result<String> {
+    info!("admin {} msg: {}", ctx.sender_id, args);
+    let (cmd, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let rest = rest.trim();
+    // 大部分指令的最后一个参数是可选的会话 id，默认当前会话
+    let mut words = rest.split_whitespace();
+    let first = words.next();
+    let second = words.next();
+    let chat_id = second.unwrap_or(&ctx.chat.id).to_string();
+
+    match cmd {
+        "status" => status(ctx).await,
+        "health" => match (first, second) {
+            (Some("reset"), Some(provider)) => {
+                if !ctx.state.reset_provider(provider).await {
+                    Err(Error::ParamError(format!("unknown provider {provider}")))?
+                }
+                Ok(format!("{} 熔断已关闭", provider))
+            }
+            (Some("reset"), None) => Err(Error::ParamError(String::from("missing provider")))?,
+            _ => health(ctx).await,
+        },
+        "enable" | "disable" => {
+            let name = first.ok_or_else(|| Error::ParamError(String::from("missing handler name")))?;
+            if !mgr.handler_names().contains(&name) {
+                Err(Error::ParamError(format!("unknown handler {name}")))?
+            }
+            let enable = cmd == "enable";
+            ctx.state
+                .update_chat(&chat_id, |c| {
+                    if enable {
+                        c.disabled.remove(name);
+                        c.enabled.insert(name.to_string());
+                    } else {
+                        c.enabled.remove(name);
+                        c.disabled.insert(name.to_string());
+                    }
+                })
+                .await;
+            Ok(format!("{} {} 已{}", chat_id, name, if enable { "开启" } else { "关闭" }))
+        }
+        "model" => {
+            let model = first.ok_or_else(|| Error::ParamError(String::from("missing model")))?;
+            let model = (model != "reset").then(|| model.to_string());
+            let reply = format!("{} 模型: {}", chat_id, model.as_deref().unwrap_or("默认"));
+            ctx.state.update_chat(&chat_id, |c| c.model = model).await;
+            Ok(reply)
+        }
+        "preset" => {
+            if rest.is_empty() {
+                Err(Error::ParamError(String::from("missing preset")))?
+            }
+            let preset = (rest != "reset").then(|| rest.to_string());
+            ctx.state.update_chat(&ctx.chat.id, |c| c.preset = preset).await;
+            Ok(String::from("提示词已更新"))
+        }
+        "mute" => {
+            let minutes = first
+                .and_then(|m| m.parse::<i64>().ok())
+                .filter(|m| *m > 0)
+                .ok_or_else(|| Error::ParamError(String::from("minutes should be a positive number")))?;
+            let until = TimeDelta::try_minutes(minutes)
+                .and_then(|d| Local::now().checked_add_signed(d))
+                .ok_or_else(|| Error::ParamError(format!("{minutes} minutes is too long")))?;
+            ctx.state.update_chat(&chat_id, |c| c.muted_until = Some(until)).await;
+            Ok(format!("{} 禁言至 {}", chat_id, until.format("%m-%d %H:%M")))
+        }
+        "unmute" => {
+            let chat_id = first.unwrap_or(&ctx.chat.id).to_string();
+            ctx.state.update_chat(&chat_id, |c| c.muted_until = None).await;
+            Ok(format!("{} 已解除禁言", chat_id))
+        }
+        "reload" => {
+            let old = ctx.state.config().await;
+            let config = ctx.state.reload().await?;
+            let mut str = format!(
+                "配置已重新加载，群聊 {} 个，私聊 {} 个",
+                config.rooms.len(),
+                config.users.len()
+            );
+            let changed = restart_required(&old, &config)?;
+            if !changed.is_empty() {
+                str += &format!("\n以下配置修改后需要重启才生效：{}", changed.join("、"));
+            }
+            Ok(str)
+        }
+        "limits" => {
+            let limiter = ctx.state.limiter();
+            if first == Some("reset") {
+                limiter.reset().await;
+                return Ok(String::from("限流计数已清空"));
+            }
+            let mut str = String::from("被限流次数:\n");
+            for (key, count) in limiter.rejections().await {
+                str += &format!("{} {}\n", key, count);
+            }
+            str += "剩余令牌:\n";
+            for (key, tokens) in limiter.tokens().await {
+                str += &format!("{} {:.1}\n", key, tokens);
+            }
+            Ok(str.trim_end().to_string())
+        }
+        "usage" => usage(ctx, first, second).await,
+        "push" => {
+            let (target, content) = rest
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| Error::ParamError(String::from("usage: push <chat id> <content>")))?;
+            let webhook = Webhook::new(ctx.config.webhook.clone(), ctx.state.http().clone());
+            if !webhook.is_enabled() {
+                Err(Error::ParamError(String::from("webhook is not configured")))?
+            }
+            let message = PushMessage {
+                is_room: ctx.config.user(target).is_none(),
+                room_id: target.to_string(),
+                content: content.trim().to_string(),
+            };
+            let delivered = webhook.push(&message).await?;
+            Ok(format!("已推送到 {} 个地址", delivered))
+        }
+        _ => Ok(USAGE.to_string()),
+    }
+}
+
+// /usage day 2025-01-02 或 /usage month 2025-01，默认今天 / 本月
+async fn usage(ctx: &Context, period: Option<&str>, date: Option<&str>) -> Result<String> {
+    let today = Local::now().date_naive();
+    let (title, (from, to)) = match period.unwrap_or("day") {
+        "day" => {
+            let date = match date {
+                Some(d) => NaiveDate::parse_from_str(d, "%Y-%m-%d")
+                    .map_err(|_| Error::ParamError(format!("invalid date {d}")))?,
+                None => today,
+            };
+            (date.format("%Y-%m-%d").to_string(), day_range(date))
+        }
+        "month" => {
+            let date = match date {
+                Some(d) => NaiveDate::parse_from_str(&format!("{d}-01"), "%Y-%m-%d")
+                    .map_err(|_| Error::ParamError(format!("invalid month {d}")))?,
+                None => today,
+            };
+            (date.format("%Y-%m").to_string(), month_range(date))
+        }
+        p => Err(Error::ParamError(format!("unknown period {p}")))?,
+    };
+
+    let summary = ctx.storage().call(move |s| s.usage().summary(from, to)).await?;
+    let total = &summary.total;
+    let mut str = format!(
+        "{} 调用 {} 次，输入 {} / 输出 {} tokens，费用 {:.4}\n",
+        title, total.calls, total.prompt_tokens, total.completion_tokens, total.cost
+    );
+    for (label, group) in [("群聊", &summary.by_room), ("用户", &summary.by_user), ("指令", &summary.by_handler)] {
+        let mut items: Vec<_> = group.iter().collect();
+        items.sort_by(|a, b| b.1.cost.total_cmp(&a.1.cost).then(b.1.calls.cmp(&a.1.calls)));
+        for (name, t) in items.into_iter().take(5) {
+            str += &format!("{} {} 调用 {} 费用 {:.4}\n", label, name, t.calls, t.cost);
+        }
+    }
+    if let Some(budget) = ctx.config.usage.monthly_budget {
+        let (from, to) = month_range(today);
+        let cost = ctx.storage().call(move |s| s.usage().cost(from, to)).await?;
+        str += &format!("本月预算 {:.2}，已用 {:.4}", budget, cost);
+    }
+    Ok(str.trim_end().to_string())
+}
+
+fn breaker_state(breaker: Breaker) -> String {
+    match breaker {
+        Breaker::Closed => String::from("正常"),
+        Breaker::Open(until) => format!("熔断至 {}", until.format("%H:%M:%S")),
+        Breaker::HalfOpen(_) => String::from("试探中"),
+    }
+}
+
+async fn health(ctx: &Context) -> Result<String> {
+    let providers = ctx.state.provider_health().await;
+    if providers.is_empty() {
+        return Ok(String::from("还没有调用过上游"));
+    }
+    let mut str = String::new();
+    for (name, p) in providers {
+        str += &format!(
+            "{} {} 成功 {} 失败 {} 连续失败 {} 拒绝 {}",
+            name,
+            breaker_state(p.breaker),
+            p.successes,
+            p.failures,
+            p.consecutive_failures,
+            p.rejected
+        );
+        if let Some(at) = p.last_ok {
+            str += &format!(" 最近成功 {}", at.format("%m-%d %H:%M"));
+        }
+        if let Some((at, e)) = p.last_error {
+            str += &format!(" 最近错误 {} {}", at.format("%m-%d %H:%M"), e);
+        }
+        str += "\n";
+    }
+    let fallbacks = &ctx.config.upstream.fallbacks;
+    let mut chains: Vec<_> = fallbacks.keys().map(|p| ctx.config.upstream.chain(p).join(" -> ")).collect();
+    chains.sort();
+    for chain in chains {
+        str += &format!("备用: {}\n", chain);
+    }
+    Ok(str.trim_end().to_string())
+}
+
+async fn status(ctx: &Context) -> Result<String> {
+    let uptime = Local::now() - ctx.state.started_at();
+    let mut str = format!(
+        "运行时间: {}天{}小时{}分\n",
+        uptime.num_days(),
+        uptime.num_hours() % 24,
+        uptime.num_minutes() % 60
+    );
+
+    str += "指令统计:\n";
+    for (name, s) in ctx.state.handler_stats().await {
+        let avg = if s.calls == 0 { 0 } else { s.total_ms / s.calls as u128 };
+        str += &format!("{} 调用 {} 失败 {} 平均 {}ms\n", name, s.calls, s.errors, avg);
+    }
+
+    str += "上游状态:\n";
+    for (name, p) in ctx.state.provider_health().await {
+        str += &format!("{} 成功 {} 失败 {}", name, p.successes, p.failures);
+        if p.breaker != Breaker::Closed {
+            str += &format!(" {}", breaker_state(p.breaker));
+        }
+        if let Some((at, e)) = p.last_error {
+            str += &format!(" 最近错误 {} {}", at.format("%m-%d %H:%M"), e);
+        }
+        str += "\n";
+    }
+
+    str += &format!("本会话模型: {}", ctx.model());
+    if let Some(until) = ctx.overrides.muted_until.filter(|_| ctx.is_muted()) {
+        str += &format!("\n禁言至 {}", until.format("%m-%d %H:%M"));
+    }
+    Ok(str)
+}
+
+#[tokio::test]
+async fn test_admin() {
+    use std::sync::Arc;
+
+    use crate::config::{ChatConfig, Config};
+    use crate::help::Help;
+    use crate::state::BotState;
+
+    let config = Config::builder()
+        .set("room_id", "room")
+        .set("admins", r#"["boss"]"#)
+        .build()
+        .unwrap();
+    let state = Arc::new(BotState::new(config).unwrap());
+    let mut mgr = HandlerMgr::new();
+    mgr.register_handler(Help::new());
+
+    let ctx = state.context(ChatConfig::new("room"), "someone").await;
+    assert!(mgr.match_handler(&ctx, "admin status").await.unwrap().is_none());
+
+    let ctx = state.context(ChatConfig::new("room"), "boss").await;
+    mgr.match_handler(&ctx, "admin disable help").await.unwrap();
+    assert!(mgr.match_handler(&ctx, "admin disable nothing").await.is_err());
+    mgr.match_handler(&ctx, "admin model gpt-4o").await.unwrap();
+    assert!(mgr.match_handler(&ctx, "admin mute 99999999999999").await.is_err());
+    assert!(!state.is_muted("room").await);
+    mgr.match_handler(&ctx, "admin mute 10").await.unwrap();
+
+    let ctx = state.context(ChatConfig::new("room"), "boss").await;
+    assert!(mgr.match_handler(&ctx, "help").await.unwrap().is_none());
+    assert_eq!(ctx.model(), "gpt-4o");
+    assert!(state.is_muted("room").await);
+
+    mgr.match_handler(&ctx, "admin unmute").await.unwrap();
+    assert!(!state.is_muted("room").await);
+    let status = mgr.match_handler(&ctx, "admin status").await.unwrap().unwrap();
+    assert!(status.contains("gpt-4o"));
+    assert!(mgr.match_handler(&ctx, "admin reload").await.is_err());
+    assert_eq!(mgr.match_handler(&ctx, "admin health").await.unwrap().unwrap(), "还没有调用过上游");
+    for _ in 0..5 {
+        state.track("okx", async { Err::<(), _>(Error::ResultError("down").into()) }).await.ok();
+    }
+    let health = mgr.match_handler(&ctx, "admin health").await.unwrap().unwrap();
+    assert!(health.starts_with("okx 熔断至 "), "{health}");
+    assert!(health.contains("连续失败 5"));
+    assert!(mgr.match_handler(&ctx, "admin health reset nope").await.is_err());
+    mgr.match_handler(&ctx, "admin health reset okx").await.unwrap();
+    assert!(mgr.match_handler(&ctx, "admin health").await.unwrap().unwrap().starts_with("okx 正常"));
+    assert!(mgr.match_handler(&ctx, "usage").await.unwrap().unwrap().contains("调用 0 次"));
+    assert!(mgr.match_handler(&ctx, "usage month 2025-01").await.unwrap().unwrap().starts_with("2025-01"));
+    assert!(mgr.match_handler(&ctx, "usage day yesterday").await.is_err());
+}
+
+#[tokio::test]
+async fn test_reload() {
+    use std::sync::Arc;
+
+    use crate::config::ChatConfig;
+    use crate::state::BotState;
+
+    let build = |model: &str, scripts: &str| {
+        Config::builder()
+            .set("room_id", "room")
+            .set("admins", r#"["boss"]"#)
+            .set("model", model)
+            .set("scripts", scripts)
+            .build()
+    };
+    let state = BotState::new(build("a", "[]").unwrap())
+        .unwrap()
+        .with_reloader(move || build("b", r#"[{"name": "ping", "type": "text", "text": "pong"}]"#));
+    let state = Arc::new(state);
+    let ctx = state.context(ChatConfig::new("room"), "boss").await;
+    let reply = on_admin(&ctx, &HandlerMgr::new(), "reload").await.unwrap();
+    assert_eq!(reply, "配置已重新加载，群聊 1 个，私聊 0 个\n以下配置修改后需要重启才生效：scripts");
+    assert_eq!(state.config().await.model, "b");
+
+    let defaults = serde_json::to_value(Config::default()).unwrap();
+    for key in RESTART_REQUIRED {
+        assert!(defaults.pointer(&format!("/{}", key.replace('.', "/"))).is_some(), "{key}");
+    }
+}
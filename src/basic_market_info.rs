@@ -1,18 +1,19 @@
 use anyhow::Result;
 use chrono::{DateTime, Duration, Utc};
 use log::info;
-use rand::{Rng, seq::IndexedRandom};
+use rand::seq::IndexedRandom;
+use serde_json::Value;
 
-use crate::{config::get_config, error::Error, handler::Handler, proxy::Message};
+use crate::{config::Config, error::Error, handler::{Context, Handler, Tool, Trigger}, upstream};
 
-static good_prompt_arrary: [&'static str; 4] = [
+static GOOD_PROMPT_ARRARY: [&str; 4] = [
     "买了。我买回了我卖掉的一切。我拥有的每一枚硬币都回来了。我完全重返了市场，激进的购买、巨大的泵，一切都那么享受。市场起飞了，我入场了。",
     "出10wu",
     "狂暴大牛牛",
     "洗脚去了",
 ];
 
-static bad_prompt_arrary: [&'static str; 5] = [
+static BAD_PROMPT_ARRARY: [&str; 5] = [
     "卖了。我卖掉了我所有的一切，我完全退出了加密货币市场，我再也受不了了。激进的倾销、操纵，巨大的崩，一切都那么激烈。加密结束了，我离开了。",
     "不怕，现货不怕",
     "先套住，再研究",
@@ -20,13 +21,17 @@ static bad_prompt_arrary: [&'static str; 5] = [
     "没关系，技术性回调, 跟他耍耍",
 ];
 
-static normal_prompt_arrary: [&'static str; 1] = ["沉淀"];
+static NORMAL_PROMPT_ARRARY: [&str; 1] = ["沉淀"];
 
+#[derive(Default)]
 pub struct BasicMakertInfo {}
 
 impl BasicMakertInfo {
     const PROMPTS_1: &'static str = "牛回";
     const PROMPTS_2: &'static str = "牛死";
+    // 只响应单纯问价格的消息，例如 "BTC多少了"、"大饼现在什么价"，其它问题交给 GPT
+    const TRIGGER: &'static str =
+        r"(?i)^\s*(btc|eth|sol|比特币|以太坊|大饼|姨太)\s*(现在|目前)?\s*(多少钱?|什么价|价格)\s*了?\s*[呢吗啊?？]?\s*$";
     pub fn new() -> Self {
         BasicMakertInfo {}
     }
@@ -34,31 +39,49 @@ impl BasicMakertInfo {
 
 #[async_trait::async_trait]
 impl Handler for BasicMakertInfo {
-    async fn on_message(&mut self, msg: &str) -> Result<String> {
+    fn name(&self) -> &str {
+        "market"
+    }
+
+    fn matches(&self, msg: &str) -> bool {
+        msg == BasicMakertInfo::PROMPTS_1 || msg == BasicMakertInfo::PROMPTS_2
+    }
+
+    fn triggers(&self) -> Vec<Trigger> {
+        vec![Trigger::regex(BasicMakertInfo::TRIGGER, 0).expect("invalid market trigger")]
+    }
+
+    fn tool(&self) -> Option<Tool> {
+        Some(Tool::new(
+            BasicMakertInfo::PROMPTS_1,
+            "查询纳指、恒指、上证、黄金和 BTC、ETH、SOL 的最新价格，以及相对前一天的涨跌幅",
+        ))
+    }
+
+    async fn on_message(&self, ctx: &Context, msg: &str) -> Result<String> {
         info!("basic market info msg: {}", msg);
-        if msg != BasicMakertInfo::PROMPTS_1 && msg != BasicMakertInfo::PROMPTS_2 {
-            return Err(Error::NotMatchError)?;
-        }
-        get_basic_info().await
+        get_basic_info(ctx).await
     }
 }
 
-pub async fn get_basic_info() -> Result<String> {
+pub async fn get_basic_info(ctx: &Context) -> Result<String> {
+    let (config, state) = (&ctx.config, &ctx.state);
+    let http = state.http();
     let mut result_list = Vec::new();
-    let ixic = get_basic_index("IXIC").await?;
+    let ixic = state.track("k780", get_basic_index(http, config, "IXIC")).await?;
     result_list.push(("纳指", ixic.0, ixic.1));
-    let hsi = get_basic_index("HSI").await?;
+    let hsi = state.track("k780", get_basic_index(http, config, "HSI")).await?;
     result_list.push(("恒指", hsi.0, hsi.1));
-    let sha = get_basic_index("SHA").await?;
+    let sha = state.track("k780", get_basic_index(http, config, "SHA")).await?;
     result_list.push(("上证", sha.0, sha.1));
-    let gold = get_gold_price_diff().await?;
+    let gold = state.track("tanshu", get_gold_price_diff(http, config)).await?;
     result_list.push(("黄金", gold.0, gold.1));
-    let btc = get_crypto_diff("BTC-USDT").await?;
-    result_list.push(("BTC", btc.0, btc.1));
-    let eth = get_crypto_diff("ETH-USDT").await?;
-    result_list.push(("ETH", eth.0, eth.1));
-    let sol = get_crypto_diff("SOL-USDT").await?;
-    result_list.push(("SOL", sol.0, sol.1));
+    // okx 不可用时依次尝试 upstream.fallbacks 中配置的备用交易所
+    let crypto = config.upstream.chain("okx");
+    for coin in ["BTC", "ETH", "SOL"] {
+        let (cur, day_ago) = state.track_chain(&crypto, |p| get_crypto_diff(http, config, p, coin)).await?;
+        result_list.push((coin, cur, day_ago));
+    }
 
     let mut cnt = 0;
     for (_, a, b) in &mut result_list {
@@ -73,22 +96,32 @@ pub async fn get_basic_info() -> Result<String> {
         str += &format!("{} {} {:.2}%\n", name, cur, diff);
     }
     if cnt >= 4 {
-        let mut rng = rand::thread_rng();
-        let v = good_prompt_arrary.choose(&mut rng).unwrap();
+        let mut rng = rand::rng();
+        let v = GOOD_PROMPT_ARRARY.choose(&mut rng).unwrap();
         str += v;
     } else if cnt <= 3 {
-        let mut rng = rand::thread_rng();
-        let v = bad_prompt_arrary.choose(&mut rng).unwrap();
+        let mut rng = rand::rng();
+        let v = BAD_PROMPT_ARRARY.choose(&mut rng).unwrap();
         str += v;
     } else {
-        let mut rng = rand::thread_rng();
-        let v = normal_prompt_arrary.choose(&mut rng).unwrap();
+        let mut rng = rand::rng();
+        let v = NORMAL_PROMPT_ARRARY.choose(&mut rng).unwrap();
         str += v;
     }
     Ok(str)
 }
 
-async fn get_basic_index(ticker: &str) -> Result<(f64, f64)> {
+// 接口返回的价格一般是字符串，也兼容数字
+fn price(value: &Value, pointer: &str, missing: &'static str) -> Result<f64> {
+    let value = value.pointer(pointer).ok_or(Error::ResultError(missing))?;
+    let price = match value {
+        Value::String(s) => s.trim().parse().ok(),
+        v => v.as_f64(),
+    };
+    Ok(price.ok_or_else(|| Error::JsonError(format!("invalid price {value} at {pointer}")))?)
+}
+
+async fn get_basic_index(http: &reqwest::Client, config: &Config, ticker: &str) -> Result<(f64, f64)> {
     let mut inxids = 0;
     if ticker == "IXIC" {
         inxids = 1114;
@@ -100,136 +133,185 @@ async fn get_basic_index(ticker: &str) -> Result<(f64, f64)> {
         inxids = 1010;
     }
     let url = format!(
-        "https://sapi.k780.com/?app=finance.globalindex&inxids={}&appkey={}&sign={}&format=json",
+        "{}/?app=finance.globalindex&inxids={}&appkey={}&sign={}&format=json",
+        config.upstream.k780.trim_end_matches('/'),
         inxids,
-        get_config().nowapi_appkey,
-        get_config().nowapi_token,
+        config.nowapi_appkey,
+        config.nowapi_token,
     );
-    let resp = reqwest::get(&url)
-        .await
-        .map_err(|e| Error::HttpError {
-            status: e.status().unwrap(),
-            url: e.url().unwrap().to_string(),
-            response: e.to_string(),
-        })?
-        .json::<serde_json::Value>()
-        .await
-        .map_err(|e| Error::JsonError(e.to_string()))?
+    let resp = upstream::json(http.get(&url).timeout(config.upstream.timeout("k780"))).await?;
+    let resp = resp
         .pointer(&format!("/result/lists/{}", inxids))
-        .ok_or(Error::ResultError("failed to get result for index"))?
-        .clone();
-
-    let previous = resp
-        .pointer("/yesy_price")
-        .ok_or(Error::ResultError("no previous info"))?
-        .as_str()
-        .unwrap()
-        .parse::<f64>()
-        .unwrap();
-    let cur = resp
-        .pointer("/last_price")
-        .ok_or(Error::ResultError("no cur info"))?
-        .as_str()
-        .unwrap()
-        .parse::<f64>()
-        .unwrap();
+        .ok_or(Error::ResultError("failed to get result for index"))?;
+
+    let previous = price(resp, "/yesy_price", "no previous info")?;
+    let cur = price(resp, "/last_price", "no cur info")?;
 
     Ok((cur, previous))
 }
 
-async fn get_crypto_price(ticker: &str, time: DateTime<Utc>) -> Result<f64> {
+async fn get_crypto_price(http: &reqwest::Client, config: &Config, ticker: &str, time: DateTime<Utc>) -> Result<f64> {
     let url = format!(
-        "https://www.okx.com/api/v5/market/history-candles?instId={}&after={}",
+        "{}/api/v5/market/history-candles?instId={}&after={}",
+        config.upstream.okx.trim_end_matches('/'),
         ticker,
         time.timestamp_millis()
     );
-    let res = reqwest::get(&url)
-        .await
-        .map_err(|e| Error::HttpError {
-            status: e.status().unwrap(),
-            url: e.url().unwrap().to_string(),
-            response: e.to_string(),
-        })?
-        .json::<serde_json::Value>()
-        .await
-        .map_err(|e| Error::JsonError(e.to_string()))?
+    let resp = upstream::json(http.get(&url).timeout(config.upstream.timeout("okx"))).await?;
+    // 每根 K 线为 [ts, open, high, low, close, ...]
+    let candle = resp
         .pointer("/data")
         .ok_or(Error::ResultError("failed to get data"))?
         .as_array()
         .ok_or(Error::JsonError("failed to parse data".to_string()))?
-        .get(0)
-        .ok_or(Error::ResultError("no data"))?
-        .as_array()
-        .unwrap()
-        .get(1)
-        .unwrap()
-        .as_str()
-        .unwrap()
-        .parse::<f64>()
-        .unwrap();
+        .first()
+        .ok_or(Error::ResultError("no data"))?;
+    price(candle, "/1", "no open price")
+}
 
-    Ok(res)
+async fn get_binance_price(http: &reqwest::Client, config: &Config, symbol: &str, time: DateTime<Utc>) -> Result<f64> {
+    let url = format!(
+        "{}/api/v3/klines?symbol={}&interval=1m&limit=1&endTime={}",
+        config.upstream.binance.trim_end_matches('/'),
+        symbol,
+        time.timestamp_millis()
+    );
+    let resp = upstream::json(http.get(&url).timeout(config.upstream.timeout("binance"))).await?;
+    // 每根 K 线为 [open time, open, high, low, close, ...]
+    let candle = resp
+        .as_array()
+        .ok_or(Error::JsonError("failed to parse klines".to_string()))?
+        .first()
+        .ok_or(Error::ResultError("no data"))?;
+    price(candle, "/1", "no open price")
 }
 
-async fn get_crypto_diff(ticker: &str) -> Result<(f64, f64)> {
-    let now = get_crypto_price(ticker, Utc::now()).await?;
-    let day_ago = get_crypto_price(ticker, Utc::now() - Duration::days(1)).await?;
-    Ok((now, day_ago))
+// provider 为 okx 或 binance，coin 例如 BTC，都以 USDT 计价
+async fn get_crypto_diff(http: &reqwest::Client, config: &Config, provider: &str, coin: &str) -> Result<(f64, f64)> {
+    let day_ago = Utc::now() - Duration::days(1);
+    match provider {
+        "okx" => {
+            let ticker = format!("{coin}-USDT");
+            let now = get_crypto_price(http, config, &ticker, Utc::now()).await?;
+            Ok((now, get_crypto_price(http, config, &ticker, day_ago).await?))
+        }
+        "binance" => {
+            let symbol = format!("{coin}USDT");
+            let now = get_binance_price(http, config, &symbol, Utc::now()).await?;
+            Ok((now, get_binance_price(http, config, &symbol, day_ago).await?))
+        }
+        p => Err(Error::ConfigError(format!("unsupported crypto provider {p}")))?,
+    }
 }
 
-async fn get_gold_price_diff() -> Result<(f64, f64)> {
+async fn get_gold_price_diff(http: &reqwest::Client, config: &Config) -> Result<(f64, f64)> {
     let url = format!(
-        "https://api.tanshuapi.com/api/gold/v1/gjgold2?key={}",
-        get_config().tanshu_apikey
+        "{}/api/gold/v1/gjgold2?key={}",
+        config.upstream.tanshu.trim_end_matches('/'),
+        config.tanshu_apikey
     );
-    let res = reqwest::get(&url)
-        .await
-        .map_err(|e| Error::HttpError {
-            status: e.status().unwrap(),
-            url: e.url().unwrap().to_string(),
-            response: e.to_string(),
-        })?
-        .json::<serde_json::Value>()
-        .await
-        .map_err(|e| Error::JsonError(e.to_string()))?
+    let resp = upstream::json(http.get(&url).timeout(config.upstream.timeout("tanshu"))).await?;
+    let res = resp
         .pointer("/data/list/XAU")
-        .ok_or(Error::ResultError("failed to get gold data"))?
-        .clone();
-    let now = res
-        .pointer("/price")
-        .ok_or(Error::ResultError("failed to get gold price"))?
-        .as_str()
-        .unwrap()
-        .parse::<f64>()
-        .unwrap();
-    let day_ago = res
-        .pointer("/lastclosingprice")
-        .ok_or(Error::ResultError("failed to get gold price"))?
-        .as_str()
-        .unwrap()
-        .parse::<f64>()
-        .unwrap();
+        .ok_or(Error::ResultError("failed to get gold data"))?;
+    let now = price(res, "/price", "failed to get gold price")?;
+    let day_ago = price(res, "/lastclosingprice", "failed to get gold price")?;
     Ok((now, day_ago))
 }
 
 #[tokio::test]
 async fn test_get_basic_index() {
-    match get_basic_index("IXIC").await {
+    let config = Config::builder().env(std::env::vars()).build().unwrap();
+    match get_basic_index(&reqwest::Client::new(), &config, "IXIC").await {
         Ok(v) => println!("{:?}", v),
         Err(e) => println!("{:?}", e),
     }
 }
 #[tokio::test]
 async fn test_get_crypto_price() {
-    match get_crypto_price("ETH-USDT", Utc::now()).await {
+    let config = Config::default();
+    match get_crypto_price(&reqwest::Client::new(), &config, "ETH-USDT", Utc::now()).await {
         Ok(v) => println!("{:?}", v),
         Err(e) => println!("{:?}", e),
     }
 }
 #[tokio::test]
 async fn test_get_gold_price_diff() {
-    match get_gold_price_diff().await {
+    let config = Config::builder().env(std::env::vars()).build().unwrap();
+    match get_gold_price_diff(&reqwest::Client::new(), &config).await {
         Ok(v) => println!("{:?}", v),
         Err(e) => println!("{:?}", e),
     }
 }
+
+#[test]
+fn test_market_trigger() {
+    let triggers = BasicMakertInfo::new().triggers();
+    let hit = |msg: &str| triggers.iter().any(|t| t.is_match(msg));
+    assert!(hit("BTC多少了"));
+    assert!(hit("大饼现在什么价"));
+    assert!(hit("eth 价格？"));
+    assert!(!hit("今天吃什么"));
+    assert!(!hit("BTC今天涨了吗"));
+    assert!(!hit("eth 跌了吗"));
+    assert!(!hit("以太坊价格会涨到多少，你怎么看"));
+}
+
+#[tokio::test]
+async fn test_market_connection_failure() {
+    use std::sync::Arc;
+    use crate::{config::ChatConfig, error::{ErrorKind, classify}, state::BotState};
+
+    let state = Arc::new(BotState::new(upstream::unreachable_config()).unwrap());
+    let ctx = state.context(ChatConfig::new("room"), "user").await;
+    let e = get_basic_info(&ctx).await.unwrap_err();
+    assert!(upstream::is_transport_error(&e), "{e:?}");
+    assert_eq!(classify(&e), ErrorKind::Upstream);
+    assert_eq!(state.provider_health().await[0].1.failures, 1);
+
+    let candle = serde_json::json!(["1700000000000", "66000.5"]);
+    assert_eq!(price(&candle, "/1", "missing").unwrap(), 66000.5);
+    assert!(price(&serde_json::json!({"p": 1.5}), "/p", "missing").unwrap() == 1.5);
+    assert!(price(&serde_json::json!({"p": "N/A"}), "/p", "missing").is_err());
+    assert!(price(&candle, "/5", "missing").is_err());
+}
+
+#[tokio::test]
+async fn test_crypto_fallback() {
+    use std::sync::Arc;
+
+    use axum::Router;
+    use axum::extract::Query;
+    use axum::routing::get;
+    use serde_json::json;
+
+    use crate::{config::ChatConfig, state::BotState};
+
+    async fn klines(Query(query): Query<Vec<(String, String)>>) -> axum::Json<Value> {
+        let end: i64 = query.iter().find(|(k, _)| k == "endTime").unwrap().1.parse().unwrap();
+        // 一天前的价格为 100，现在为 110
+        let open = if end < Utc::now().timestamp_millis() - 3_600_000 { "100" } else { "110" };
+        axum::Json(json!([[end, open, "0", "0", "0"]]))
+    }
+    let app = Router::new().route("/api/v3/klines", get(klines));
+    let addr = crate::testing::serve(app).await;
+
+    let mut config = upstream::unreachable_config();
+    config.upstream.binance = format!("http://{addr}");
+    config.upstream.fallbacks.insert(String::from("okx"), vec![String::from("binance")]);
+    let http = reqwest::Client::new();
+    assert_eq!(get_crypto_diff(&http, &config, "binance", "BTC").await.unwrap(), (110.0, 100.0));
+    assert!(get_crypto_diff(&http, &config, "nope", "BTC").await.is_err());
+
+    let state = Arc::new(BotState::new(config).unwrap());
+    let ctx = state.context(ChatConfig::new("room"), "user").await;
+    let crypto = ctx.config.upstream.chain("okx");
+    let diff = state
+        .track_chain(&crypto, |p| get_crypto_diff(&http, &ctx.config, p, "SOL"))
+        .await
+        .unwrap();
+    assert_eq!(diff, (110.0, 100.0));
+    let health = state.provider_health().await;
+    assert_eq!((health[0].0.as_str(), health[0].1.successes), ("binance", 1));
+    assert_eq!((health[1].0.as_str(), health[1].1.failures), ("okx", 1));
+}
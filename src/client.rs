@@ -0,0 +1,307 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{Context as _, Result};
+use clap::Parser;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint};
+
+use wechat_bot_core::config::{Args, Config};
+use wechat_bot_core::dispatch::Dispatcher;
+use wechat_bot_core::handler::HandlerMgr;
+use wechat_bot_core::proxy::proxy_client::ProxyClient;
+use wechat_bot_core::proxy::{Message, MessageResp};
+use wechat_bot_core::state::BotState;
+use wechat_bot_core::{plugin, script};
+
+/// 不需要微信账号的调试客户端：连接 gRPC 服务，或者在本进程内直接运行指令
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct ClientArgs {
+    /// gRPC 服务地址
+    #[arg(long, default_value = "http://[::1]:50051")]
+    server: String,
+
+    /// 对应服务端的 server.auth_token
+    #[arg(long)]
+    token: Option<String>,
+
+    /// 服务端开启 TLS 时用于校验的 CA 证书
+    #[arg(long, value_name = "FILE")]
+    ca: Option<PathBuf>,
+
+    /// 不连接服务，按 --config 等参数在本进程内处理消息，使用内存数据库
+    #[arg(long)]
+    local: bool,
+
+    /// 群聊 id，不设置时为私聊
+    #[arg(long)]
+    room: Option<String>,
+
+    /// 发送者 id
+    #[arg(long, default_value = "cli")]
+    sender: String,
+
+    /// 群聊时是否 @ 机器人
+    #[arg(long)]
+    mention: bool,
+
+    /// 从文件读取输入，每行一条消息或一条 : 指令，# 开头的行是注释
+    #[arg(long, value_name = "FILE")]
+    script: Option<PathBuf>,
+
+    #[command(flatten)]
+    config: Args,
+}
+
+const HELP: &str = r#":room <id>     切换到群聊
+:dm            切换到私聊
+:sender <id>   切换发送者
+:mention [on|off]  切换是否 @ 机器人
+:status        当前会话
+:quit          退出
+其它输入作为消息内容发送"#;
+
+#[derive(Debug, PartialEq)]
+enum Input<'a> {
+    Send(&'a str),
+    Room(&'a str),
+    Direct,
+    Sender(&'a str),
+    Mention(Option<bool>),
+    Status,
+    Help,
+    Quit,
+    Skip,
+}
+
+fn parse_line(line: &str) -> Input<'_> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Input::Skip;
+    }
+    let Some(cmd) = line.strip_prefix(':') else {
+        return Input::Send(line);
+    };
+    let (cmd, arg) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+    let arg = arg.trim();
+    match (cmd, arg) {
+        ("room", id) if !id.is_empty() => Input::Room(id),
+        ("dm", _) => Input::Direct,
+        ("sender", id) if !id.is_empty() => Input::Sender(id),
+        ("mention", "on") => Input::Mention(Some(true)),
+        ("mention", "off") => Input::Mention(Some(false)),
+        ("mention", "") => Input::Mention(None),
+        ("status", _) => Input::Status,
+        ("quit" | "q" | "exit", _) => Input::Quit,
+        _ => Input::Help,
+    }
+}
+
+#[derive(Clone)]
+struct TokenInterceptor(Option<MetadataValue<Ascii>>);
+
+impl Interceptor for TokenInterceptor {
+    fn call(&mut self, mut req: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+        if let Some(token) = &self.0 {
+            req.metadata_mut().insert("authorization", token.clone());
+        }
+        Ok(req)
+    }
+}
+
+enum Backend {
+    Grpc(ProxyClient<InterceptedService<Channel, TokenInterceptor>>),
+    Local(Dispatcher),
+}
+
+impl Backend {
+    async fn connect(args: &ClientArgs) -> Result<Self> {
+        if args.local {
+            let config = Config::from_args(&args.config)?;
+            let mut handlers = HandlerMgr::builtin();
+            script::register_scripts(&mut handlers, &config)?;
+            plugin::register_plugins(&mut handlers, &config.plugins)?;
+            let max_in_flight = config.server.max_in_flight;
+            let state = Arc::new(BotState::new(config)?);
+            return Ok(Backend::Local(Dispatcher::new(state, handlers, max_in_flight)));
+        }
+        let mut endpoint = Endpoint::from_shared(args.server.clone())?;
+        if let Some(ca) = &args.ca {
+            let pem = std::fs::read(ca).with_context(|| format!("failed to read {}", ca.display()))?;
+            endpoint = endpoint.tls_config(ClientTlsConfig::new().ca_certificate(Certificate::from_pem(pem)))?;
+        }
+        let channel = endpoint
+            .connect()
+            .await
+            .with_context(|| format!("failed to connect to {}", args.server))?;
+        let token = match &args.token {
+            Some(token) => Some(format!("Bearer {token}").parse()?),
+            None => None,
+        };
+        Ok(Backend::Grpc(ProxyClient::with_interceptor(channel, TokenInterceptor(token))))
+    }
+
+    async fn send(&mut self, message: Message) -> Result<MessageResp> {
+        match self {
+            Backend::Grpc(client) => Ok(client.on_message(message).await?.into_inner()),
+            Backend::Local(dispatcher) => Ok(dispatcher.handle(message).await),
+        }
+    }
+}
+
+struct Session {
+    room: Option<String>,
+    sender: String,
+    mention: bool,
+    sent: u64,
+}
+
+impl Session {
+    fn prompt(&self) -> String {
+        match &self.room {
+            Some(room) => format!("{}@{}{}> ", self.sender, room, if self.mention { " (@)" } else { "" }),
+            None => format!("{}> ", self.sender),
+        }
+    }
+
+    fn message(&mut self, content: &str) -> Message {
+        self.sent += 1;
+        Message {
+            is_room: self.room.is_some(),
+            is_memtioned: self.mention,
+            room_id: self.room.clone().unwrap_or_default(),
+            content: content.to_string(),
+            sender_id: self.sender.clone(),
+            message_id: format!("cli-{}-{}", std::process::id(), self.sent),
+        }
+    }
+
+    /// 返回 false 表示退出
+    async fn handle(&mut self, backend: &mut Backend, line: &str, out: &mut Vec<String>) -> bool {
+        match parse_line(line) {
+            Input::Send(content) => {
+                let start = Instant::now();
+                let message = self.message(content);
+                match backend.send(message).await {
+                    Ok(resp) => {
+                        out.push(format!("[{}] {}ms", resp.code().as_str_name(), start.elapsed().as_millis()));
+                        if !resp.response.is_empty() {
+                            out.push(resp.response);
+                        }
+                    }
+                    Err(e) => out.push(format!("[error] {e:#}")),
+                }
+            }
+            Input::Room(id) => self.room = Some(id.to_string()),
+            Input::Direct => self.room = None,
+            Input::Sender(id) => self.sender = id.to_string(),
+            Input::Mention(on) => self.mention = on.unwrap_or(!self.mention),
+            Input::Status => out.push(format!(
+                "room: {}, sender: {}, mention: {}",
+                self.room.as_deref().unwrap_or("(私聊)"),
+                self.sender,
+                self.mention
+            )),
+            Input::Help => out.push(HELP.to_string()),
+            Input::Quit => return false,
+            Input::Skip => {}
+        }
+        true
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = ClientArgs::parse();
+    let mut backend = Backend::connect(&args).await?;
+    let mut session = Session {
+        room: args.room.clone(),
+        sender: args.sender.clone(),
+        mention: args.mention,
+        sent: 0,
+    };
+    let script = match &args.script {
+        Some(path) => Some(std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?),
+        None => None,
+    };
+    let mut script_lines = script.as_deref().map(str::lines);
+    let scripted = script_lines.is_some();
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+    loop {
+        let line = match &mut script_lines {
+            Some(lines) => lines.next().map(str::to_string),
+            None => {
+                stdout.write_all(session.prompt().as_bytes()).await?;
+                stdout.flush().await?;
+                stdin.next_line().await?
+            }
+        };
+        let Some(line) = line else {
+            break;
+        };
+        // 脚本模式下回显输入，方便对照输出
+        if scripted && parse_line(&line) != Input::Skip {
+            stdout.write_all(format!("{}{}\n", session.prompt(), line.trim()).as_bytes()).await?;
+        }
+        let mut out = Vec::new();
+        let running = session.handle(&mut backend, &line, &mut out).await;
+        for line in out {
+            stdout.write_all(format!("{line}\n").as_bytes()).await?;
+        }
+        if !running {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_parse_line() {
+    assert_eq!(parse_line("  /help "), Input::Send("/help"));
+    assert_eq!(parse_line("# comment"), Input::Skip);
+    assert_eq!(parse_line(""), Input::Skip);
+    assert_eq!(parse_line(":room  abc "), Input::Room("abc"));
+    assert_eq!(parse_line(":room"), Input::Help);
+    assert_eq!(parse_line(":dm"), Input::Direct);
+    assert_eq!(parse_line(":mention"), Input::Mention(None));
+    assert_eq!(parse_line(":mention off"), Input::Mention(Some(false)));
+    assert_eq!(parse_line(":q"), Input::Quit);
+}
+
+#[tokio::test]
+async fn test_local_session() {
+    let config = Config::builder()
+        .set("rooms", r#"[{"id": "room"}]"#)
+        .set("users", r#"[{"id": "alice"}]"#)
+        .build()
+        .unwrap();
+    let mut handlers = HandlerMgr::new();
+    handlers.register_handler(wechat_bot_core::help::Help::new());
+    let mut backend = Backend::Local(Dispatcher::new(Arc::new(BotState::new(config).unwrap()), handlers, 1));
+    let mut session = Session {
+        room: None,
+        sender: String::from("alice"),
+        mention: false,
+        sent: 0,
+    };
+    let mut out = Vec::new();
+    for line in ["/help", ":room room", "/help", ":mention", "/nope", ":quit"] {
+        if !session.handle(&mut backend, line, &mut out).await {
+            break;
+        }
+    }
+    assert!(out[0].starts_with("[Ok] "));
+    assert!(out[1].starts_with("可用指令列表"));
+    // 群聊没有 @ 机器人
+    assert!(out[2].starts_with("[Ignore] "));
+    assert!(out[3].starts_with("[BadRequest] "));
+    assert_eq!(out[4], "没有这个指令，发送 /help 看看");
+    assert_eq!(session.prompt(), "alice@room (@)> ");
+    assert_eq!(session.sent, 3);
+}
@@ -1,52 +1,1093 @@
-use serde::Deserialize;
-use std::sync::OnceLock;
-use std::{fs, process};
+use anyhow::{Context, Result};
 use clap::Parser;
-use std::path::PathBuf;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs};
+
+use crate::error::Error;
 
 // 定义命令行参数结构
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
+pub struct Args {
+    /// 配置文件，按扩展名识别 json / toml
     #[arg(short, long, value_name = "FILE", default_value = "config.json")]
-    config: PathBuf,
+    pub config: PathBuf,
+
+    /// 密钥文件，内容会覆盖配置文件中的同名字段
+    #[arg(long, value_name = "FILE")]
+    pub secrets: Option<PathBuf>,
+
+    /// 不读取配置文件，只使用默认值、环境变量和命令行参数
+    #[arg(long)]
+    pub env_only: bool,
+
+    /// 覆盖单个配置项，例如 --set model=gpt-4o
+    #[arg(short, long = "set", value_name = "KEY=VALUE")]
+    pub set: Vec<String>,
+}
+
+/// 单个群聊或私聊的设置
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ChatConfig {
+    /// 群聊为 room id，私聊为用户 id
+    pub id: String,
+    pub name: String,
+    /// 启用的指令（handler 名），不配置表示全部启用
+    pub commands: Option<Vec<String>>,
+    /// GPT 前置提示词，不配置时使用全局 preset
+    pub preset: Option<String>,
+    /// GPT 模型，不配置时使用全局 model
+    pub model: Option<String>,
+    /// 群聊中是否必须 @ 机器人，私聊忽略
+    pub require_mention: bool,
+    /// 启用自由文本触发的指令（handler 名），不配置表示全部关闭
+    pub triggers: Option<Vec<String>>,
+    /// 没有 @ 机器人时是否也响应自由文本触发
+    pub trigger_without_mention: bool,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        ChatConfig {
+            id: String::new(),
+            name: String::new(),
+            commands: None,
+            preset: None,
+            model: None,
+            require_mention: true,
+            triggers: None,
+            trigger_without_mention: false,
+        }
+    }
+}
+
+impl ChatConfig {
+    pub fn new(id: impl Into<String>) -> Self {
+        ChatConfig {
+            id: id.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn command_enabled(&self, name: &str) -> bool {
+        self.commands
+            .as_ref()
+            .is_none_or(|commands| commands.iter().any(|c| c == name))
+    }
+
+    /// 自由文本容易误伤普通聊天，只有在 triggers 中列出的指令才会被触发
+    pub fn trigger_enabled(&self, name: &str) -> bool {
+        self.triggers
+            .as_ref()
+            .is_some_and(|triggers| triggers.iter().any(|t| t == name))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct BucketConfig {
+    /// 桶容量，即允许的突发次数
+    pub capacity: u32,
+    /// 每分钟回填的令牌数
+    pub per_minute: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// 被限流时不回复
+    pub silent: bool,
+    /// 限流时的回复，{seconds} 会被替换成需要等待的秒数
+    pub reply: String,
+    pub user: Option<BucketConfig>,
+    pub room: Option<BucketConfig>,
+    /// 按指令名限流，GPT 兜底回复的指令名为 gpt
+    pub commands: HashMap<String, BucketConfig>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            silent: false,
+            reply: String::from("慢点慢点，{seconds} 秒后再来"),
+            user: Some(BucketConfig { capacity: 5, per_minute: 10.0 }),
+            room: Some(BucketConfig { capacity: 20, per_minute: 30.0 }),
+            commands: HashMap::new(),
+        }
+    }
+}
+
+/// 每百万 token 的价格
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// gRPC 监听地址，启动时读取
+    pub listen: String,
+    /// 同时处理的消息数上限，超出的消息排队等待，启动时读取
+    pub max_in_flight: usize,
+    /// 单条消息的处理时限（秒），包括排队时间
+    pub request_timeout: u64,
+    /// 不配置时使用明文 gRPC
+    pub tls: Option<TlsConfig>,
+    /// 客户端需要在 authorization: Bearer <token> 或 x-bot-token 中带上的密钥，不配置时不校验
+    #[serde(deserialize_with = "lenient_opt")]
+    pub auth_token: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: String::from("[::1]:50051"),
+            max_in_flight: 16,
+            request_timeout: 60,
+            tls: None,
+            auth_token: None,
+        }
+    }
+}
+
+/// PEM 格式的证书和私钥
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TlsConfig {
+    #[serde(deserialize_with = "lenient")]
+    pub cert: PathBuf,
+    #[serde(deserialize_with = "lenient")]
+    pub key: PathBuf,
+    /// 配置后要求客户端出示由该 CA 签发的证书（mTLS）
+    #[serde(default, deserialize_with = "lenient_opt")]
+    pub client_ca: Option<PathBuf>,
+}
+
+/// 可选的 http 服务，和 gRPC 使用不同端口
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// 监听地址，不配置时不启动，启动时读取
+    #[serde(deserialize_with = "lenient_opt")]
+    pub listen: Option<String>,
+    /// 是否提供 Prometheus 的 /metrics
+    pub metrics: bool,
+    /// 是否提供 POST /message，和 gRPC 的 OnMessage 相同，校验 server.auth_token
+    pub message: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            listen: None,
+            metrics: true,
+            message: false,
+        }
+    }
+}
+
+/// Telegram Bot API 兼容的接入，配置后启动时开始长轮询 getUpdates
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TelegramConfig {
+    #[serde(deserialize_with = "lenient")]
+    pub token: String,
+    /// Bot API 地址，可以换成自建的 telegram-bot-api 或兼容服务
+    #[serde(deserialize_with = "lenient")]
+    pub api: String,
+    /// getUpdates 长轮询时长（秒）
+    pub poll_timeout: u64,
+}
+
+impl Default for TelegramConfig {
+    fn default() -> Self {
+        TelegramConfig {
+            token: String::new(),
+            api: String::from("https://api.telegram.org"),
+            poll_timeout: 30,
+        }
+    }
+}
+
+/// Matrix client-server API 兼容的接入，配置后启动时开始长轮询 /sync
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct MatrixConfig {
+    #[serde(deserialize_with = "lenient")]
+    pub homeserver: String,
+    #[serde(deserialize_with = "lenient")]
+    pub access_token: String,
+    /// 机器人自己的用户 id，例如 @bot:matrix.org，用于识别 @ 和忽略自己的消息
+    #[serde(deserialize_with = "lenient")]
+    pub user_id: String,
+    /// /sync 长轮询时长（秒）
+    pub poll_timeout: u64,
+}
+
+impl Default for MatrixConfig {
+    fn default() -> Self {
+        MatrixConfig {
+            homeserver: String::from("https://matrix.org"),
+            access_token: String::new(),
+            user_id: String::new(),
+            poll_timeout: 30,
+        }
+    }
+}
+
+/// 主动推送消息时回调的地址，gRPC 和 http 都只能由桥接端发起请求
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// 每条推送 POST 到全部地址
+    pub urls: Vec<String>,
+    /// 配置后在 X-Bot-Signature 中带上 sha256=<hex(HMAC-SHA256(secret, body))>
+    #[serde(deserialize_with = "lenient_opt")]
+    pub secret: Option<String>,
+    /// 单次请求时限（秒）
+    pub timeout: u64,
+    /// 失败后的重试次数
+    pub retries: u32,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            urls: Vec::new(),
+            secret: None,
+            timeout: 10,
+            retries: 2,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    /// 每行一个 json，带上请求的 span 字段
+    Json,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// 日志配置，启动时读取
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+    /// 过滤规则，例如 "info" 或者 "info,wechat_bot_core::plugin=debug"，设置了 RUST_LOG 时以 RUST_LOG 为准
+    pub level: String,
+    pub format: LogFormat,
+    /// 是否输出到标准输出
+    pub console: bool,
+    /// 日志文件，按 rotation 加上日期后缀，设为空字符串时不写文件
+    #[serde(deserialize_with = "lenient_opt")]
+    pub file: Option<PathBuf>,
+    pub rotation: LogRotation,
+    /// 保留的历史日志文件数
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: String::from("info"),
+            format: LogFormat::Text,
+            console: true,
+            file: Some(PathBuf::from("logs/app.log")),
+            rotation: LogRotation::Daily,
+            max_files: 5,
+        }
+    }
+}
+
+/// 配置中定义的简单指令
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ScriptCommand {
+    /// 指令名，用于按群开关和限流
+    pub name: String,
+    /// 匹配的 / 指令，不配置时为 name；指令后面的内容是 {args}
+    #[serde(default)]
+    pub prompts: Vec<String>,
+    /// 自由文本触发的关键词
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    #[serde(flatten)]
+    pub action: ScriptAction,
+}
+
+/// 文本中可以使用 {sender}、{chat}、{date}、{args} 占位符（text 除外）
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScriptAction {
+    /// 固定回复
+    Text { text: String },
+    /// 随机选一条
+    Random { choices: Vec<String> },
+    Template { template: String },
+    /// GET 请求 url，用 JSON pointer 取出结果，template 中用 {value} 引用，不配置时直接回复结果
+    Http {
+        url: String,
+        pointer: String,
+        #[serde(default)]
+        template: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PluginConfig {
+    /// 插件目录，每个子目录包含一个 plugin.toml，不配置时不加载插件
+    #[serde(deserialize_with = "lenient_opt")]
+    pub dir: Option<PathBuf>,
+    /// 只加载这些插件，不配置表示全部加载
+    pub enabled: Option<Vec<String>>,
+    /// 单次调用的时限（秒），插件自己配置的 timeout 不能超过它
+    pub timeout: u64,
+    /// 插件单次输出的最大字节数
+    pub max_output: usize,
+    /// 插件进程的 CPU 时间上限（秒，RLIMIT_CPU），0 表示不限制
+    pub cpu_seconds: u64,
+    /// 插件进程的虚拟内存上限（字节，RLIMIT_AS），0 表示不限制
+    pub max_memory: u64,
+    /// 运行机器人的用户最多能有的进程数（RLIMIT_NPROC，按用户计算），0 表示不限制
+    pub max_processes: u64,
+}
+
+impl Default for PluginConfig {
+    fn default() -> Self {
+        PluginConfig {
+            dir: None,
+            enabled: None,
+            timeout: 10,
+            max_output: 64 * 1024,
+            cpu_seconds: 10,
+            max_memory: 1024 * 1024 * 1024,
+            max_processes: 256,
+        }
+    }
+}
+
+/// 各类错误回复给用户的内容，{error} 会被替换为错误信息，为空时不回复
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ErrorReplies {
+    pub user: String,
+    pub unknown_command: String,
+    pub upstream: String,
+    pub internal: String,
+}
+
+impl Default for ErrorReplies {
+    fn default() -> Self {
+        ErrorReplies {
+            user: String::from("{error}"),
+            unknown_command: String::from("没有这个指令，发送 /help 看看"),
+            upstream: String::from("接口开小差了，等会再试"),
+            internal: String::from("哦豁"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// SQLite 数据库文件，为 ":memory:" 时使用内存数据库，重启后丢失
+    #[serde(deserialize_with = "lenient")]
+    pub path: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            path: PathBuf::from("data/bot.db"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct UsageConfig {
+    /// 每月预算，超出后关闭 GPT 兜底回复
+    pub monthly_budget: Option<f64>,
+    /// 超出预算时的回复，为空则不回复
+    pub budget_reply: String,
+    /// 按模型名配置价格
+    pub pricing: HashMap<String, ModelPrice>,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        UsageConfig {
+            monthly_budget: None,
+            budget_reply: String::from("本月额度用完了，下个月再聊"),
+            pricing: HashMap::new(),
+        }
+    }
+}
+
+impl UsageConfig {
+    /// 接口返回的模型名可能带有日期后缀（例如 gpt-4o-mini-2024-07-18），精确匹配不到时使用最长的前缀
+    pub fn price(&self, model: &str) -> Option<&ModelPrice> {
+        self.pricing.get(model).or_else(|| {
+            self.pricing
+                .iter()
+                .filter(|(name, _)| model.strip_prefix(name.as_str()).is_some_and(|rest| rest.starts_with('-')))
+                .max_by_key(|(name, _)| name.len())
+                .map(|(_, price)| price)
+        })
+    }
+}
+
+/// 各个上游接口的地址，以及所有模块共用的 http 客户端设置
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct UpstreamConfig {
+    /// 指数行情（nowapi）
+    pub k780: String,
+    /// 加密货币行情
+    pub okx: String,
+    /// 加密货币行情的备用接口，需要在 fallbacks 中配置才会使用
+    pub binance: String,
+    /// 黄金价格
+    pub tanshu: String,
+    /// 老黄历
+    pub juhe: String,
+    /// 英超赛程
+    pub aceodds: String,
+    /// 默认的单次请求时限（秒）
+    pub timeout: u64,
+    /// 建立连接的时限（秒），启动时读取
+    pub connect_timeout: u64,
+    /// 按上游单独设置的请求时限（秒），键为上面的字段名、gpt 或 script
+    pub timeouts: HashMap<String, u64>,
+    /// 出站代理，例如 http://127.0.0.1:7890 或 socks5://...，启动时读取
+    #[serde(deserialize_with = "lenient_opt")]
+    pub proxy: Option<String>,
+    /// 只有这些上游走代理，可以是上面的字段名、gpt、telegram、matrix，为空时全部走代理，启动时读取
+    pub proxy_providers: Vec<String>,
+    /// 启动时读取
+    pub user_agent: String,
+    /// 上游失败时依次尝试的备用上游，例如 okx = ["binance"]，目前只有 okx 支持
+    pub fallbacks: HashMap<String, Vec<String>>,
+    pub breaker: BreakerConfig,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            k780: String::from("https://sapi.k780.com"),
+            okx: String::from("https://www.okx.com"),
+            binance: String::from("https://api.binance.com"),
+            tanshu: String::from("https://api.tanshuapi.com"),
+            juhe: String::from("http://v.juhe.cn"),
+            aceodds: String::from("https://www.aceodds.com"),
+            timeout: 10,
+            connect_timeout: 5,
+            timeouts: HashMap::from([(String::from("gpt"), 60)]),
+            proxy: None,
+            proxy_providers: Vec::new(),
+            user_agent: String::from(concat!("wechat-bot-core/", env!("CARGO_PKG_VERSION"))),
+            fallbacks: HashMap::new(),
+            breaker: BreakerConfig::default(),
+        }
+    }
 }
 
+impl UpstreamConfig {
+    /// 该上游的请求时限，没有单独设置时使用 timeout
+    pub fn timeout(&self, provider: &str) -> Duration {
+        Duration::from_secs(self.timeouts.get(provider).copied().unwrap_or(self.timeout))
+    }
 
-#[derive(Debug, Deserialize, Clone)]
+    /// 依次尝试的上游：provider 本身，然后是它的备用上游
+    pub fn chain<'a>(&'a self, provider: &'a str) -> Vec<&'a str> {
+        let fallbacks = self.fallbacks.get(provider).into_iter().flatten();
+        std::iter::once(provider).chain(fallbacks.map(String::as_str)).collect()
+    }
+}
+
+/// 每个上游的熔断：连续失败 failures 次后打开，cooldown 秒内的调用直接失败，
+/// 之后放行一次试探，成功则恢复，失败则重新打开
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct BreakerConfig {
+    pub enabled: bool,
+    pub failures: u32,
+    pub cooldown: u64,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            enabled: true,
+            failures: 5,
+            cooldown: 60,
+        }
+    }
+}
+
+/// 录制线上流量，用于离线回放，启动时读取
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RecordConfig {
+    /// 追加写入匿名化后的消息和回复（JSONL），不配置时不录制
+    #[serde(deserialize_with = "lenient_opt")]
+    pub traffic: Option<PathBuf>,
+    /// 追加写入上游接口的请求和响应（JSONL），不配置时不录制
+    #[serde(deserialize_with = "lenient_opt")]
+    pub upstream: Option<PathBuf>,
+}
+
+/// GPT 兜底回复时把指令作为工具（function calling）交给模型调用
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ToolsConfig {
+    pub enabled: bool,
+    /// 一条消息最多几轮工具调用，之后要求模型直接回答
+    pub max_depth: u32,
+}
+
+impl Default for ToolsConfig {
+    fn default() -> Self {
+        ToolsConfig {
+            enabled: true,
+            max_depth: 3,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct Config {
+    /// 已废弃，请使用 rooms
     pub room_id : String,
+    /// 已废弃，请使用 rooms
     pub room_id_dev: String,
 
+    /// 响应的群聊
+    pub rooms: Vec<ChatConfig>,
+    /// 允许私聊的用户
+    pub users: Vec<ChatConfig>,
+    /// 可以使用 /admin 指令的用户 id
+    pub admins: Vec<String>,
+
+    pub server: ServerConfig,
+    pub http: HttpConfig,
+    pub log: LogConfig,
+    pub webhook: WebhookConfig,
+    /// 不配置时不接入 Telegram
+    pub telegram: Option<TelegramConfig>,
+    /// 不配置时不接入 Matrix
+    pub matrix: Option<MatrixConfig>,
+    pub rate_limit: RateLimitConfig,
+    pub errors: ErrorReplies,
+    pub plugins: PluginConfig,
+    pub scripts: Vec<ScriptCommand>,
+    /// 额外的指令文件（json/toml），其中的 scripts 会追加到配置的 scripts 之后
+    #[serde(deserialize_with = "lenient_opt")]
+    pub scripts_file: Option<PathBuf>,
+    pub usage: UsageConfig,
+    pub storage: StorageConfig,
+    pub upstream: UpstreamConfig,
+    pub record: RecordConfig,
+    pub tools: ToolsConfig,
+
     pub gpt_api: String,
     pub gpt_token: String,
     pub model: String,
     pub user_id: String,
 
-    pub tieba_pre_set: String,
+    /// 默认的 GPT 前置提示词，兼容旧字段 tieba_pre_set
+    #[serde(alias = "tieba_pre_set")]
+    pub preset: String,
 
     pub nowapi_token: String,
     pub nowapi_appkey: String,
     pub huangli_apikey: String,
     pub tanshu_apikey: String,
+
+    /// 密钥文件路径，--secrets 和 WECHAT_BOT_SECRETS_FILE 优先
+    #[serde(deserialize_with = "lenient_opt")]
+    pub secrets_file: Option<PathBuf>,
 }
 
-// 全局单例实例
-static CONFIG: OnceLock<Config> = OnceLock::new();
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            room_id: String::new(),
+            room_id_dev: String::new(),
+            rooms: Vec::new(),
+            users: Vec::new(),
+            admins: Vec::new(),
+            server: ServerConfig::default(),
+            http: HttpConfig::default(),
+            log: LogConfig::default(),
+            webhook: WebhookConfig::default(),
+            telegram: None,
+            matrix: None,
+            rate_limit: RateLimitConfig::default(),
+            errors: ErrorReplies::default(),
+            plugins: PluginConfig::default(),
+            scripts: Vec::new(),
+            scripts_file: None,
+            usage: UsageConfig::default(),
+            storage: StorageConfig::default(),
+            upstream: UpstreamConfig::default(),
+            record: RecordConfig::default(),
+            tools: ToolsConfig::default(),
+            gpt_api: String::from("https://api.302.ai/v1"),
+            gpt_token: String::new(),
+            model: String::from("gpt-4o-mini"),
+            user_id: String::new(),
+            preset: String::new(),
+            nowapi_token: String::new(),
+            nowapi_appkey: String::new(),
+            huangli_apikey: String::new(),
+            tanshu_apikey: String::new(),
+            secrets_file: None,
+        }
+    }
+}
 
-/// 获取配置单例（首次调用时初始化）
-pub fn get_config() -> &'static Config {
-    CONFIG.get_or_init(|| {
-        let args = Args::parse();
-        let config_path = args.config;
-        let config_str = fs::read_to_string(&config_path).unwrap_or_else(|e| {
-            eprintln!("Failed to read config.json: {e}");
-            process::exit(1);
-        });
+/// 分层构建配置，测试里可以直接在内存中拼出一份配置
+pub struct ConfigBuilder {
+    merged: Value,
+    vars: Vec<(String, String)>,
+    sets: Vec<(String, String)>,
+}
 
-        serde_json::from_str(&config_str).unwrap_or_else(|e| {
-            eprintln!("Invalid config format: {e}");
-            process::exit(1);
-        })
-    })
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        ConfigBuilder {
+            merged: Value::Object(Map::new()),
+            vars: Vec::new(),
+            sets: Vec::new(),
+        }
+    }
+
+    /// 合并一个配置文件，按扩展名识别 json / toml
+    pub fn file(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        merge(&mut self.merged, read_file(path.as_ref())?);
+        Ok(self)
+    }
+
+    /// 合并一段配置文本，先按 json 解析，失败再按 toml 解析
+    pub fn source(mut self, content: &str) -> Result<Self> {
+        merge(&mut self.merged, parse_str(content)?);
+        Ok(self)
+    }
+
+    /// 合并 secrets_file 指向的密钥文件（如果有）
+    pub fn secrets(self) -> Result<Self> {
+        let path = self
+            .vars
+            .iter()
+            .find(|(k, _)| k == "secrets_file")
+            .map(|(_, v)| PathBuf::from(v))
+            .or_else(|| {
+                self.merged
+                    .pointer("/secrets_file")
+                    .and_then(Value::as_str)
+                    .map(PathBuf::from)
+            });
+        match path {
+            Some(path) => self.file(path),
+            None => Ok(self),
+        }
+    }
+
+    /// 记录 WECHAT_BOT_* 变量，在 build 时覆盖文件中的值
+    pub fn env(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.vars.extend(vars.into_iter().filter_map(|(k, v)| {
+            Some((k.strip_prefix(Config::ENV_PREFIX)?.to_lowercase(), v))
+        }));
+        self
+    }
+
+    /// 覆盖单个字段，嵌套字段用 . 分隔，优先级最高
+    pub fn set(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.sets.push((key.to_string(), value.into()));
+        self
+    }
+
+    /// 生成配置，不做必填校验
+    pub fn build(self) -> Result<Config> {
+        let defaults = serde_json::to_value(Config::default())?;
+        let mut merged = self.merged;
+        for (key, raw) in &self.vars {
+            set_path(&mut merged, &defaults, key.split("__"), raw);
+        }
+        for (key, raw) in &self.sets {
+            set_path(&mut merged, &defaults, key.split('.'), raw);
+        }
+        let mut config: Config =
+            serde_json::from_value(merged).map_err(|e| Error::ConfigError(e.to_string()))?;
+        config.normalize();
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// 环境变量前缀，嵌套字段用双下划线分隔，例如 WECHAT_BOT_GPT_TOKEN
+    pub const ENV_PREFIX: &'static str = "WECHAT_BOT_";
+
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+
+    /// 不应该出现在日志中的配置值
+    pub fn secret_values(&self) -> Vec<String> {
+        [
+            &self.gpt_token,
+            &self.nowapi_token,
+            &self.nowapi_appkey,
+            &self.huangli_apikey,
+            &self.tanshu_apikey,
+        ]
+        .into_iter()
+        .chain(self.server.auth_token.as_ref())
+        .chain(self.webhook.secret.as_ref())
+        .chain(self.telegram.as_ref().map(|t| &t.token))
+        .chain(self.matrix.as_ref().map(|m| &m.access_token))
+        .filter(|s| !s.is_empty())
+        .cloned()
+        .collect()
+    }
+
+    /// 读取配置文件及其 secrets_file，不读取环境变量和命令行
+    pub fn load(path: impl AsRef<Path>) -> Result<Config> {
+        let config = ConfigBuilder::new().file(path)?.secrets()?.build()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 按 默认值 -> 配置文件 -> 密钥文件 -> 环境变量 -> 命令行 的顺序合并配置
+    pub fn from_args(args: &Args) -> Result<Config> {
+        Config::from_args_and_vars(args, env::vars())
+    }
+
+    fn from_args_and_vars(args: &Args, vars: impl IntoIterator<Item = (String, String)>) -> Result<Config> {
+        let mut builder = ConfigBuilder::new().env(vars);
+        if !args.env_only {
+            builder = builder.file(&args.config)?;
+        }
+        builder = match &args.secrets {
+            Some(path) => builder.file(path)?,
+            None => builder.secrets()?,
+        };
+        for kv in &args.set {
+            let (key, raw) = kv
+                .split_once('=')
+                .ok_or_else(|| Error::ParamError(format!("expect KEY=VALUE, got {kv}")))?;
+            builder = builder.set(key, raw);
+        }
+        let config = builder.build()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn room(&self, id: &str) -> Option<&ChatConfig> {
+        self.rooms.iter().find(|r| r.id == id)
+    }
+
+    pub fn user(&self, id: &str) -> Option<&ChatConfig> {
+        self.users.iter().find(|u| u.id == id)
+    }
+
+    pub fn preset_for<'a>(&'a self, chat: &'a ChatConfig) -> &'a str {
+        chat.preset.as_deref().unwrap_or(&self.preset)
+    }
+
+    pub fn model_for<'a>(&'a self, chat: &'a ChatConfig) -> &'a str {
+        chat.model.as_deref().unwrap_or(&self.model)
+    }
+
+    // 把旧的 room_id / room_id_dev 转成 rooms 中的条目
+    fn normalize(&mut self) {
+        for id in [self.room_id.clone(), self.room_id_dev.clone()] {
+            if !id.is_empty() && self.room(&id).is_none() {
+                self.rooms.push(ChatConfig::new(id));
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.rooms.is_empty() && self.users.is_empty() {
+            Err(Error::ConfigError(String::from("at least one of rooms / users is required")))?
+        }
+        if let Some(chat) = self.rooms.iter().chain(self.users.iter()).find(|c| c.id.is_empty()) {
+            Err(Error::ConfigError(format!("chat {:?} has empty id", chat.name)))?
+        }
+        let limit = &self.rate_limit;
+        let buckets = [("user", &limit.user), ("room", &limit.room)]
+            .into_iter()
+            .filter_map(|(name, b)| b.as_ref().map(|b| (name, b)))
+            .chain(limit.commands.iter().map(|(name, b)| (name.as_str(), b)));
+        for (name, bucket) in buckets {
+            if !(bucket.per_minute > 0.0 && bucket.per_minute.is_finite()) {
+                Err(Error::ConfigError(format!("rate_limit {name} per_minute should be positive")))?
+            }
+        }
+        // 没有价格的模型费用按 0 计算，预算永远不会用完
+        if self.usage.monthly_budget.is_some() {
+            let models = self.rooms.iter().chain(self.users.iter()).map(|c| self.model_for(c));
+            if let Some(model) = std::iter::once(self.model.as_str()).chain(models).find(|m| self.usage.price(m).is_none()) {
+                Err(Error::ConfigError(format!("usage.monthly_budget is set but model {model} has no usage.pricing")))?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let config = ConfigBuilder::new().source(s)?.build()?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+pub(crate) fn read_file(path: &Path) -> Result<Value> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let value = match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str::<Value>(&content).map_err(|e| Error::ConfigError(e.to_string()))?,
+        _ => serde_json::from_str::<Value>(&content).map_err(|e| Error::ConfigError(e.to_string()))?,
+    };
+    Ok(value)
+}
+
+fn parse_str(content: &str) -> Result<Value> {
+    if let Ok(value) = serde_json::from_str::<Value>(content) {
+        return Ok(value);
+    }
+    let value = toml::from_str::<Value>(content).map_err(|e| Error::ConfigError(e.to_string()))?;
+    Ok(value)
+}
+
+fn merge(dst: &mut Value, src: Value) {
+    match (dst, src) {
+        (Value::Object(dst), Value::Object(src)) => {
+            for (k, v) in src {
+                merge(dst.entry(k).or_insert(Value::Null), v);
+            }
+        }
+        (dst, src) => *dst = src,
+    }
+}
+
+// 环境变量和 --set 的值按 json 解析，例如 "123456" 会变成数字，默认值为 null 的字符串字段用它来接受数字和布尔值
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    String(String),
+    Number(serde_json::Number),
+    Bool(bool),
+}
+
+impl From<Scalar> for String {
+    fn from(value: Scalar) -> Self {
+        match value {
+            Scalar::String(s) => s,
+            Scalar::Number(n) => n.to_string(),
+            Scalar::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+fn lenient<'de, D: Deserializer<'de>, T: From<String>>(deserializer: D) -> Result<T, D::Error> {
+    Ok(T::from(String::from(Scalar::deserialize(deserializer)?)))
+}
+
+fn lenient_opt<'de, D: Deserializer<'de>, T: From<String>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    Ok(Option::<Scalar>::deserialize(deserializer)?.map(|v| T::from(String::from(v))))
+}
+
+// 字符串字段保持原样，其它字段按 json 解析，例如 true / 10 / ["a","b"]
+fn set_path<'a>(dst: &mut Value, defaults: &Value, mut path: impl Iterator<Item = &'a str>, raw: &str) {
+    let Some(key) = path.next() else {
+        *dst = match defaults {
+            Value::String(_) => Value::String(raw.to_string()),
+            _ => serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string())),
+        };
+        return;
+    };
+    if !dst.is_object() {
+        *dst = Value::Object(Map::new());
+    }
+    let child = dst
+        .as_object_mut()
+        .unwrap()
+        .entry(key.to_string())
+        .or_insert(Value::Null);
+    set_path(child, defaults.get(key).unwrap_or(&Value::Null), path, raw);
+}
+
+#[test]
+fn test_load_layered() {
+    let dir = crate::testing::TempDir::new("config");
+    let config_path = dir.join("config.toml");
+    let secrets_path = dir.join("secrets.json");
+    fs::write(&config_path, "room_id = \"room\"\nmodel = \"from-file\"\ngpt_token = \"file-token\"\n").unwrap();
+    fs::write(&secrets_path, r#"{"gpt_token": "secret-token", "nowapi_token": "123"}"#).unwrap();
+
+    let args = Args::parse_from([
+        "bot",
+        "--config",
+        config_path.to_str().unwrap(),
+        "--secrets",
+        secrets_path.to_str().unwrap(),
+        "--set",
+        "user_id=from-cli",
+    ]);
+    let vars = vec![
+        (String::from("WECHAT_BOT_MODEL"), String::from("from-env")),
+        (String::from("WECHAT_BOT_HUANGLI_APIKEY"), String::from("456")),
+        (String::from("UNRELATED"), String::from("x")),
+    ];
+    let config = Config::from_args_and_vars(&args, vars).unwrap();
+    assert_eq!(config.rooms[0].id, "room");
+    assert_eq!(config.model, "from-env");
+    assert_eq!(config.gpt_token, "secret-token");
+    assert_eq!(config.nowapi_token, "123");
+    assert_eq!(config.huangli_apikey, "456");
+    assert_eq!(config.user_id, "from-cli");
+    assert_eq!(config.gpt_api, Config::default().gpt_api);
+
+    let args = Args::parse_from(["bot", "--env-only"]);
+    assert!(Config::from_args_and_vars(&args, vec![]).is_err());
+    let vars = vec![(String::from("WECHAT_BOT_ROOM_ID"), String::from("env-room"))];
+    let config = Config::from_args_and_vars(&args, vars).unwrap();
+    assert_eq!(config.room("env-room").unwrap().id, "env-room");
+
+    // 纯数字、布尔值的密钥仍按字符串处理
+    let args = Args::parse_from(["bot", "--env-only", "--set", "webhook.secret=true", "--set", "matrix.access_token=789"]);
+    let vars = vec![
+        (String::from("WECHAT_BOT_ROOM_ID"), String::from("env-room")),
+        (String::from("WECHAT_BOT_SERVER__AUTH_TOKEN"), String::from("123456")),
+        (String::from("WECHAT_BOT_TELEGRAM__TOKEN"), String::from("42")),
+    ];
+    let config = Config::from_args_and_vars(&args, vars).unwrap();
+    assert_eq!(config.server.auth_token.as_deref(), Some("123456"));
+    assert_eq!(config.telegram.unwrap().token, "42");
+    assert_eq!(config.webhook.secret.as_deref(), Some("true"));
+    assert_eq!(config.matrix.unwrap().access_token, "789");
+}
+
+#[test]
+fn test_config_from_str() {
+    let config: Config = r#"{"room_id": "room", "model": "gpt-4o"}"#.parse().unwrap();
+    assert!(config.room("room").is_some());
+    assert_eq!(config.model, "gpt-4o");
+    let config: Config = "room_id = \"room\"".parse().unwrap();
+    assert_eq!(config.model, Config::default().model);
+    assert!("model = \"gpt-4o\"".parse::<Config>().is_err());
+
+    let config = Config::builder().set("room_id", "room").set("gpt_token", "123").build().unwrap();
+    assert_eq!(config.gpt_token, "123");
+    assert_eq!(config.storage.path, PathBuf::from("data/bot.db"));
+    let config = Config::builder().set("storage.path", ":memory:").build().unwrap();
+    assert_eq!(config.storage.path, PathBuf::from(crate::storage::Storage::MEMORY));
+}
+
+#[test]
+fn test_chat_config() {
+    let config: Config = r#"
+preset = "default preset"
+room_id = "legacy"
+
+[[rooms]]
+id = "a"
+commands = ["help"]
+model = "gpt-4o"
+
+[[rooms]]
+id = "b"
+preset = "room preset"
+require_mention = false
+triggers = ["market"]
+trigger_without_mention = true
+
+[[users]]
+id = "wxid_admin"
+"#
+    .parse()
+    .unwrap();
+    assert_eq!(config.rooms.len(), 3);
+    let a = config.room("a").unwrap();
+    assert!(a.command_enabled("help"));
+    assert!(!a.command_enabled("market"));
+    assert!(a.require_mention);
+    assert_eq!(config.model_for(a), "gpt-4o");
+    assert_eq!(config.preset_for(a), "default preset");
+    let b = config.room("b").unwrap();
+    assert!(b.command_enabled("market"));
+    assert!(!b.require_mention);
+    assert!(b.trigger_enabled("market"));
+    assert!(!b.trigger_enabled("huangli"));
+    assert!(!a.trigger_enabled("huangli") && !a.trigger_without_mention);
+    assert_eq!(config.preset_for(b), "room preset");
+    assert!(config.room("legacy").is_some());
+    assert!(config.user("wxid_admin").is_some());
+    assert!(config.room("wxid_admin").is_none());
+
+    let config: Config = r#"{"tieba_pre_set": "legacy preset", "users": [{"id": "u"}]}"#.parse().unwrap();
+    assert_eq!(config.preset, "legacy preset");
+    assert!(r#"{"rooms": [{"name": "no id"}]}"#.parse::<Config>().is_err());
+    let zero = r#"{"users": [{"id": "u"}], "rate_limit": {"commands": {"gpt": {"capacity": 1, "per_minute": 0}}}}"#;
+    assert!(zero.parse::<Config>().is_err());
+
+    // 配置了预算时，用到的模型都要有价格
+    let priced = r#"{"model": "gpt-4o-mini", "users": [{"id": "u"}, {"id": "v", "model": "gpt-4o"}],
+        "usage": {"monthly_budget": 10, "pricing": {"gpt-4o-mini": {"prompt": 0.15, "completion": 0.6}}}}"#;
+    let err = priced.parse::<Config>().unwrap_err();
+    assert!(err.to_string().contains("model gpt-4o has no usage.pricing"), "{err}");
+    assert!(priced.replace(r#", "model": "gpt-4o""#, "").parse::<Config>().is_ok());
+}
+
+#[test]
+fn test_rate_limit_config() {
+    let config: Config = r#"
+room_id = "room"
+
+[rate_limit]
+silent = true
+room = { capacity = 1, per_minute = 2 }
+
+[rate_limit.commands]
+gpt = { capacity = 3, per_minute = 0.5 }
+"#
+    .parse()
+    .unwrap();
+    assert!(config.rate_limit.enabled);
+    assert!(config.rate_limit.silent);
+    assert_eq!(config.rate_limit.room.unwrap().capacity, 1);
+    assert_eq!(config.rate_limit.commands["gpt"].per_minute, 0.5);
+    assert_eq!(config.rate_limit.user.unwrap().capacity, 5);
+
+    let config = Config::builder().set("rate_limit.enabled", "false").build().unwrap();
+    assert!(!config.rate_limit.enabled);
 }
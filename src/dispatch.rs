@@ -0,0 +1,453 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use serde_json::json;
+use tokio::sync::Semaphore;
+use tracing::{Instrument, Span, field, info_span};
+
+use crate::error::{Error, ErrorKind, classify};
+use crate::gpt::GPTProxy;
+use crate::handler::{Context, HandlerMgr};
+use crate::middleware::{Middleware, Request, RequestLog, RoomFilter, ignore};
+use crate::proxy::{Message, MessageResp, RespCode};
+use crate::state::BotState;
+
+/// 消息处理入口：中间件链 + 指令分发 + GPT 兜底，与具体的传输方式无关
+pub struct Dispatcher {
+    state: Arc<BotState>,
+    handlers: HandlerMgr,
+    middlewares: Vec<Box<dyn Middleware>>,
+    in_flight: Semaphore,
+}
+
+impl Dispatcher {
+    /// 默认带有 RequestLog 和 RoomFilter 两个中间件
+    pub fn new(state: Arc<BotState>, handlers: HandlerMgr, max_in_flight: usize) -> Self {
+        Dispatcher {
+            state,
+            handlers,
+            middlewares: vec![Box::new(RequestLog::new()), Box::new(RoomFilter::new())],
+            in_flight: Semaphore::new(max_in_flight.max(1)),
+        }
+    }
+
+    /// 追加一个中间件，在已有的中间件之后执行
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// 不带任何中间件，需要自己加上 RoomFilter 或者其它生成 Context 的中间件
+    pub fn without_middlewares(mut self) -> Self {
+        self.middlewares.clear();
+        self
+    }
+
+    pub fn state(&self) -> &Arc<BotState> {
+        &self.state
+    }
+
+    pub fn middleware_names(&self) -> Vec<&'static str> {
+        self.middlewares.iter().map(|m| m.name()).collect()
+    }
+
+    /// 处理过程中的日志都带有 room、sender、message_id 和 command 字段
+    pub async fn handle(&self, message: Message) -> MessageResp {
+        let req = Request::new(self.state.clone(), message);
+        let span = info_span!(
+            "request",
+            room = %req.message.room_id,
+            sender = %req.message.sender_id,
+            message_id = %req.message.message_id,
+            command = field::Empty,
+        );
+        self.handle_request(req).instrument(span).await
+    }
+
+    async fn handle_request(&self, mut req: Request) -> MessageResp {
+        let mut entered = 0;
+        let mut resp = None;
+        for m in &self.middlewares {
+            entered += 1;
+            if let Some(r) = m.before(&mut req).await {
+                resp = Some(r);
+                break;
+            }
+        }
+        let mut resp = match resp {
+            Some(resp) => resp,
+            None => self.run(&req).await,
+        };
+        for m in self.middlewares[..entered].iter().rev() {
+            m.after(&req, &mut resp).await;
+        }
+        resp
+    }
+
+    async fn run(&self, req: &Request) -> MessageResp {
+        let Some(ctx) = &req.ctx else {
+            return ignore();
+        };
+        // 排队等待也算在时限内，超时后丢弃这条消息的处理
+        let seconds = ctx.config.server.request_timeout;
+        let handle = async {
+            let _permit = self.in_flight.acquire().await;
+            self.dispatch(ctx, &req.content, req.addressed).await
+        };
+        match tokio::time::timeout(Duration::from_secs(seconds), handle).await {
+            Ok(resp) => resp,
+            Err(_) => {
+                error!("message timed out after {}s: {}", seconds, req.content);
+                self.state.metrics().observe_request(&ctx.chat.id, "dispatch", "timeout");
+                error_resp(ctx, &Error::Timeout(seconds).into())
+            }
+        }
+    }
+
+    // 依次尝试 / 指令、自由文本触发，最后才交给 GPT；
+    // 开启工具时 @ 机器人的消息直接交给 GPT，由模型决定是否调用指令，触发只用于没有 @ 的消息
+    async fn dispatch(&self, ctx: &Context, content: &str, addressed: bool) -> MessageResp {
+        // Follow the instruction
+        if addressed && let Some(instruction) = content.strip_prefix("/") {
+            return match self.handlers.match_handler(ctx, instruction).await {
+                Ok(Some(v)) => reply(RespCode::Ok, v),
+                Ok(None) => {
+                    Span::current().record("command", "unknown");
+                    self.state.metrics().observe_request(&ctx.chat.id, "unknown", "user_error");
+                    reply(RespCode::BadRequest, ctx.config.errors.unknown_command.clone())
+                }
+                Err(e) => {
+                    error!("failed to execute instruction, err: {:?}", e);
+                    error_resp(ctx, &e)
+                }
+            };
+        }
+        if !(addressed && ctx.config.tools.enabled) {
+            match self.handlers.match_trigger(ctx, content).await {
+                Ok(Some(v)) => return reply(RespCode::Ok, v),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("failed to execute trigger, err: {:?}", e);
+                    return error_resp(ctx, &e);
+                }
+            }
+        }
+        if !addressed {
+            return ignore();
+        }
+        // Respond to the message
+        Span::current().record("command", "gpt");
+        let start = Instant::now();
+        let res = self.chat(ctx, content).await;
+        self.state.metrics().observe_handler("gpt", start.elapsed());
+        ctx.observe_request("gpt", &res);
+        match res {
+            Ok(v) => reply(RespCode::Ok, v),
+            Err(e) => {
+                error!("failed to query gpt, err: {:?}", e);
+                error_resp(ctx, &e)
+            }
+        }
+    }
+
+    async fn chat(&self, ctx: &Context, content: &str) -> anyhow::Result<String> {
+        ctx.check_rate_limit("gpt").await?;
+        if ctx.budget_exceeded().await {
+            return Ok(ctx.config.usage.budget_reply.clone());
+        }
+        let gpt_proxy = GPTProxy::new(
+            self.state.http().clone(),
+            ctx.model().to_string(),
+            ctx.config.user_id.clone(),
+            ctx.config.gpt_api.clone(),
+            ctx.config.gpt_token.clone(),
+            ctx.preset().to_string(),
+        )
+        .timeout(ctx.config.upstream.timeout("gpt"));
+        let tools = if ctx.config.tools.enabled { self.handlers.tools(ctx) } else { Vec::new() };
+        let max_depth = ctx.config.tools.max_depth;
+        let mut messages = gpt_proxy.messages(content);
+        // 模型可以多轮调用工具，超过 max_depth 轮后要求直接回答
+        for depth in 0..=max_depth {
+            let call_tools = depth < max_depth;
+            let completion = self
+                .state
+                .track("llm", gpt_proxy.complete(&messages, &tools, call_tools))
+                .await?;
+            ctx.record_usage("gpt", &completion).await;
+            if completion.tool_calls.is_empty() {
+                return Ok(completion.content);
+            }
+            if !call_tools {
+                break;
+            }
+            messages.push(completion.message());
+            for call in &completion.tool_calls {
+                info!("gpt calls tool {} with {}", call.name, call.arguments);
+                // 工具出错时把错误告诉模型，由它决定怎么回答
+                let result = match self.handlers.call_tool(ctx, &call.name, &call.arguments).await {
+                    Ok(v) => v,
+                    Err(e) => format!("error: {e}"),
+                };
+                messages.push(json!({"role": "tool", "tool_call_id": call.id, "content": result}));
+            }
+            Span::current().record("command", "gpt");
+        }
+        Err(Error::ResultError("gpt kept calling tools"))?
+    }
+}
+
+pub fn reply(code: RespCode, response: String) -> MessageResp {
+    // 回复内容为空时不回复，但保留错误码方便客户端统计
+    let code = if response.is_empty() && code == RespCode::Ok { RespCode::Ignore } else { code };
+    MessageResp {
+        code: code.into(),
+        response,
+    }
+}
+
+/// 按错误分类选择回复内容和错误码
+pub fn error_resp(ctx: &Context, e: &anyhow::Error) -> MessageResp {
+    let replies = &ctx.config.errors;
+    let (code, template) = match classify(e) {
+        ErrorKind::RateLimited => {
+            let limit = &ctx.config.rate_limit;
+            let seconds = match e.downcast_ref::<Error>() {
+                Some(Error::RateLimited(seconds)) => *seconds,
+                _ => 1,
+            };
+            let response = if limit.silent {
+                String::new()
+            } else {
+                limit.reply.replace("{seconds}", &seconds.to_string())
+            };
+            return reply(RespCode::RateLimited, response);
+        }
+        ErrorKind::User => (RespCode::BadRequest, &replies.user),
+        ErrorKind::Upstream => (RespCode::Unavailable, &replies.upstream),
+        ErrorKind::Internal => (RespCode::Coruption, &replies.internal),
+    };
+    reply(code, template.replace("{error}", &e.to_string()))
+}
+
+#[tokio::test]
+async fn test_dispatcher() {
+    use std::sync::Mutex;
+
+    use crate::config::Config;
+    use crate::help::Help;
+
+    // 记录调用顺序，拦截含有 "secret" 的消息
+    struct Guard {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Middleware for Guard {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        async fn before(&self, req: &mut Request) -> Option<MessageResp> {
+            self.calls.lock().unwrap().push(format!("before {}", self.name));
+            req.content.contains("secret").then(|| reply(RespCode::BadRequest, String::from("no")))
+        }
+
+        async fn after(&self, _req: &Request, resp: &mut MessageResp) {
+            self.calls.lock().unwrap().push(format!("after {}", self.name));
+            resp.response += "!";
+        }
+    }
+
+    let config = Config::builder()
+        .set("rooms", r#"[{"id": "room"}]"#)
+        .set("users", r#"[{"id": "friend"}]"#)
+        .build()
+        .unwrap();
+    let state = Arc::new(BotState::new(config).unwrap());
+    let mut handlers = HandlerMgr::new();
+    handlers.register_handler(Help::new());
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let dispatcher = Dispatcher::new(state, handlers, 4)
+        .middleware(Guard { name: "a", calls: calls.clone() })
+        .middleware(Guard { name: "b", calls: calls.clone() });
+    assert_eq!(dispatcher.middleware_names(), vec!["request_log", "room_filter", "a", "b"]);
+
+    let message = |room: &str, mentioned: bool, sender: &str, content: &str| Message {
+        is_room: !room.is_empty(),
+        is_memtioned: mentioned,
+        room_id: room.to_string(),
+        content: content.to_string(),
+        sender_id: sender.to_string(),
+        message_id: String::new(),
+    };
+
+    let resp = dispatcher.handle(message("room", true, "user", "/help")).await;
+    assert_eq!(resp.code(), RespCode::Ok);
+    assert!(resp.response.starts_with("可用指令列表") && resp.response.ends_with("!!"));
+    assert_eq!(*calls.lock().unwrap(), vec!["before a", "before b", "after b", "after a"]);
+
+    // 没有 @、不在配置里的群都被 RoomFilter 拦下，不会进入后面的中间件
+    calls.lock().unwrap().clear();
+    assert_eq!(dispatcher.handle(message("room", false, "user", "/help")).await.code(), RespCode::Ignore);
+    assert_eq!(dispatcher.handle(message("other", true, "user", "/help")).await.code(), RespCode::Ignore);
+    assert!(calls.lock().unwrap().is_empty());
+
+    // a 短路时 b 不执行，但 a 自己的 after 仍然执行
+    let resp = dispatcher.handle(message("", false, "friend", "/secret")).await;
+    assert_eq!(resp.code(), RespCode::BadRequest);
+    assert_eq!(resp.response, "no!");
+    assert_eq!(*calls.lock().unwrap(), vec!["before a", "after a"]);
+
+    let resp = dispatcher.handle(message("", false, "friend", "/unknown")).await;
+    assert_eq!(resp.code(), RespCode::BadRequest);
+}
+
+#[tokio::test]
+async fn test_chat_tools() {
+    use std::sync::Mutex;
+
+    use axum::extract::State;
+    use axum::{Json, Router, routing::post};
+    use serde_json::Value;
+
+    use crate::config::Config;
+
+    // 问 BTC 时调用一次 market 后按结果回答，问 ETH 时一直调用；tool_choice 为 none 时直接回答
+    type Requests = Arc<Mutex<Vec<Value>>>;
+    async fn completions(State(requests): State<Requests>, Json(body): Json<Value>) -> Json<Value> {
+        requests.lock().unwrap().push(body.clone());
+        let messages = body["messages"].as_array().unwrap();
+        let last = messages.last().unwrap();
+        let asked_btc = messages[0]["content"].as_str().unwrap().contains("BTC");
+        let message = if body["tool_choice"] == "none" {
+            json!({"role": "assistant", "content": "不查了"})
+        } else if last["role"] == "tool" && asked_btc {
+            json!({"role": "assistant", "content": "行情暂时查不到"})
+        } else {
+            let call = json!({"id": format!("call_{}", messages.len()), "type": "function",
+                "function": {"name": "market", "arguments": "{}"}});
+            json!({"role": "assistant", "content": null, "tool_calls": [call]})
+        };
+        Json(json!({"choices": [{"message": message}], "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}}))
+    }
+    let requests = Requests::default();
+    let app = Router::new().route("/chat/completions", post(completions)).with_state(requests.clone());
+    let addr = crate::testing::serve(app).await;
+
+    // market 触发开启，但 @ 机器人的消息在开启工具时先交给 GPT
+    let build = |max_depth: &str| {
+        let mut config = Config::builder()
+            .set("users", r#"[{"id": "friend", "triggers": ["market"]}]"#)
+            .set("gpt_api", format!("http://{addr}"))
+            .set("tools.max_depth", max_depth)
+            .build()
+            .unwrap();
+        config.upstream = crate::upstream::unreachable_config().upstream;
+        Dispatcher::new(Arc::new(BotState::new(config).unwrap()), HandlerMgr::builtin(), 1)
+    };
+    let message = |content: &str| Message {
+        is_room: false,
+        is_memtioned: false,
+        room_id: String::new(),
+        content: content.to_string(),
+        sender_id: String::from("friend"),
+        message_id: String::new(),
+    };
+
+    let dispatcher = build("3");
+    let resp = dispatcher.handle(message("BTC今天涨了吗")).await;
+    assert_eq!(resp.code(), RespCode::Ok);
+    assert_eq!(resp.response, "行情暂时查不到");
+    {
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let tools: Vec<_> = requests[0]["tools"].as_array().unwrap().iter().map(|t| &t["function"]["name"]).collect();
+        assert_eq!(tools, vec!["market", "gamble", "huangli"]);
+        let history = requests[1]["messages"].as_array().unwrap();
+        assert_eq!(history[1]["tool_calls"][0]["id"], "call_1");
+        assert_eq!(history[2]["tool_call_id"], "call_1");
+        // 上游都连不上，工具把错误交给模型
+        assert!(history[2]["content"].as_str().unwrap().starts_with("error: "));
+    }
+    let stats = dispatcher.state().handler_stats().await;
+    assert_eq!(stats.iter().find(|(name, _)| name == "market").unwrap().1.calls, 1);
+
+    // 能命中 market 触发的消息也先交给 GPT
+    requests.lock().unwrap().clear();
+    let resp = dispatcher.handle(message("BTC多少了")).await;
+    assert_eq!(resp.response, "行情暂时查不到");
+    assert_eq!(requests.lock().unwrap().len(), 2);
+
+    // 一直要求调用工具时，第 max_depth 轮之后要求直接回答
+    requests.lock().unwrap().clear();
+    let resp = build("2").handle(message("ETH今天涨了吗")).await;
+    assert_eq!(resp.response, "不查了");
+    {
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2]["messages"].as_array().unwrap().len(), 5);
+        assert!(requests[1].get("tool_choice").is_none());
+    }
+
+    requests.lock().unwrap().clear();
+    let resp = build("0").handle(message("BTC今天涨了吗")).await;
+    assert_eq!(resp.response, "不查了");
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_builtin_triggers() {
+    use std::sync::Mutex;
+
+    use axum::extract::State;
+    use axum::{Json, Router, routing::post};
+    use serde_json::Value;
+
+    use crate::config::Config;
+
+    type Requests = Arc<Mutex<Vec<String>>>;
+    async fn completions(State(requests): State<Requests>, Json(body): Json<Value>) -> Json<Value> {
+        let messages = body["messages"].as_array().unwrap();
+        let content = messages.last().unwrap()["content"].as_str().unwrap().to_string();
+        requests.lock().unwrap().push(content.clone());
+        let message = json!({"role": "assistant", "content": format!("gpt: {content}")});
+        Json(json!({"choices": [{"message": message}], "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}}))
+    }
+    let requests = Requests::default();
+    let app = Router::new().route("/chat/completions", post(completions)).with_state(requests.clone());
+    let addr = crate::testing::serve(app).await;
+
+    let mut config = Config::builder()
+        .set("rooms", r#"[{"id": "room", "triggers": ["market"], "trigger_without_mention": true}]"#)
+        .set("users", r#"[{"id": "friend"}]"#)
+        .set("gpt_api", format!("http://{addr}"))
+        .build()
+        .unwrap();
+    config.upstream = crate::upstream::unreachable_config().upstream;
+    let dispatcher = Dispatcher::new(Arc::new(BotState::new(config).unwrap()), HandlerMgr::builtin(), 4);
+    let message = |room: &str, content: &str| Message {
+        is_room: !room.is_empty(),
+        is_memtioned: false,
+        room_id: room.to_string(),
+        content: content.to_string(),
+        sender_id: String::from("friend"),
+        message_id: String::new(),
+    };
+
+    // 私聊没有配置 triggers，问价格也交给 GPT
+    let resp = dispatcher.handle(message("", "BTC今天涨了吗")).await;
+    assert_eq!(resp.response, "gpt: BTC今天涨了吗");
+    let resp = dispatcher.handle(message("", "BTC多少了")).await;
+    assert_eq!(resp.response, "gpt: BTC多少了");
+    assert_eq!(*requests.lock().unwrap(), vec!["BTC今天涨了吗", "BTC多少了"]);
+
+    // 群里启用了 market 触发，但只响应单纯问价格的消息
+    requests.lock().unwrap().clear();
+    assert_eq!(dispatcher.handle(message("room", "BTC今天涨了吗")).await.code(), RespCode::Ignore);
+    let resp = dispatcher.handle(message("room", "BTC多少了")).await;
+    assert_eq!(resp.code(), RespCode::Unavailable);
+    assert!(requests.lock().unwrap().is_empty());
+}
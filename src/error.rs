@@ -1,6 +1,5 @@
 use reqwest::StatusCode;
 use thiserror::Error;
-use anyhow::{Context, Result};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -8,6 +7,10 @@ pub enum Error {
     #[error("Invalid parameters: {0}")]
     ParamError(String),
 
+    /// 连接失败、超时等没有拿到响应的错误
+    #[error("request to {url} failed: {message}")]
+    TransportError { url: String, message: String },
+
     #[error("HTTP error {status}: {url}")]
     HttpError {
         status: StatusCode,
@@ -15,12 +18,131 @@ pub enum Error {
         response: String,
     },
 
+    /// 响应无法按预期的格式解析
+    #[error("failed to decode response from {url}: {message}")]
+    DecodeError { url: String, message: String },
+
+    #[error("Config error: {0}")]
+    ConfigError(String),
+
     #[error("JSON parse error: {0}")]
     JsonError(String),
 
     #[error("get error: {0}")]
     ResultError(&'static str),
 
-    #[error("not match")]
-    NotMatchError,
+    #[error("rate limited, retry after {0}s")]
+    RateLimited(u64),
+
+    /// 该上游熔断中，没有发出请求
+    #[error("{provider} is unavailable, retry after {seconds}s")]
+    CircuitOpen { provider: String, seconds: u64 },
+
+    #[error("timed out after {0}s")]
+    Timeout(u64),
+
+    #[error("plugin {plugin} error: {message}")]
+    PluginError { plugin: String, message: String },
+}
+
+/// 错误分类，决定回复给用户的内容和 RespCode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// 用户输入有误
+    User,
+    /// 上游接口不可用或返回了无法解析的数据
+    Upstream,
+    RateLimited,
+    Internal,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::ParamError(_) => ErrorKind::User,
+            Error::TransportError { .. }
+            | Error::HttpError { .. }
+            | Error::DecodeError { .. }
+            | Error::JsonError(_)
+            | Error::ResultError(_)
+            | Error::CircuitOpen { .. }
+            | Error::Timeout(_)
+            | Error::PluginError { .. } => ErrorKind::Upstream,
+            Error::RateLimited(_) => ErrorKind::RateLimited,
+            Error::ConfigError(_) => ErrorKind::Internal,
+        }
+    }
+}
+
+impl Error {
+    /// 按 reqwest 的错误类型转换为 TransportError、HttpError 或 DecodeError
+    ///
+    /// url 去掉了 query，避免其中的密钥出现在错误信息里
+    pub fn from_reqwest(e: reqwest::Error) -> Self {
+        let url = e.url().map(|u| strip_query(u.as_str())).unwrap_or_default();
+        let (status, decode) = (e.status(), e.is_decode());
+        let e = e.without_url();
+        let mut message = e.to_string();
+        let mut source = std::error::Error::source(&e);
+        while let Some(cause) = source {
+            message += &format!(": {cause}");
+            source = cause.source();
+        }
+        match status {
+            _ if decode => Error::DecodeError { url, message },
+            Some(status) => Error::HttpError {
+                status,
+                url,
+                response: message,
+            },
+            None => Error::TransportError { url, message },
+        }
+    }
+}
+
+/// 去掉 url 的 query 和 fragment，路径中 Telegram 的 /bot<token>/ 换成 /bot***/
+pub fn strip_query(url: &str) -> String {
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    // token 的格式为 <bot id>:<secret>
+    let is_token = |s: &str| {
+        s.strip_prefix("bot")
+            .and_then(|t| t.split_once(':'))
+            .is_some_and(|(id, _)| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+    };
+    url.split('/')
+        .map(|s| if is_token(s) { "bot***" } else { s })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// 对 anyhow::Error 分类，未知的错误视为内部错误
+pub fn classify(e: &anyhow::Error) -> ErrorKind {
+    if let Some(e) = e.downcast_ref::<Error>() {
+        return e.kind();
+    }
+    if e.downcast_ref::<reqwest::Error>().is_some() {
+        return ErrorKind::Upstream;
+    }
+    ErrorKind::Internal
+}
+
+#[test]
+fn test_classify() {
+    assert_eq!(classify(&Error::ParamError(String::from("x")).into()), ErrorKind::User);
+    assert_eq!(classify(&Error::ResultError("no data").into()), ErrorKind::Upstream);
+    assert_eq!(classify(&Error::RateLimited(3).into()), ErrorKind::RateLimited);
+    assert_eq!(classify(&anyhow::anyhow!("boom")), ErrorKind::Internal);
+    let e: anyhow::Error = serde_json::from_str::<u8>("x").unwrap_err().into();
+    assert_eq!(classify(&e), ErrorKind::Internal);
+    let e = Error::TransportError {
+        url: String::from("http://x"),
+        message: String::from("timed out"),
+    };
+    assert_eq!(classify(&e.into()), ErrorKind::Upstream);
+    assert_eq!(strip_query("https://x.com/a?key=1#b"), "https://x.com/a");
+    assert_eq!(
+        strip_query("https://api.telegram.org/bot123:AA-b_c/getMe"),
+        "https://api.telegram.org/bot***/getMe"
+    );
+    assert_eq!(strip_query("https://x.com/bots/bot:1"), "https://x.com/bots/bot:1");
 }
\ No newline at end of file
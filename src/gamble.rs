@@ -1,14 +1,12 @@
-use std::sync::Arc;
-
 use anyhow::Result;
 use log::info;
 use nipper::Document;
 
 use crate::{
-    config::get_config,
-    error::Error,
-    gpt::{self, GPTProxy},
-    handler::Handler,
+    config::Config,
+    gpt,
+    handler::{Context, Handler, Tool},
+    upstream,
 };
 
 #[derive(Debug, serde::Deserialize)]
@@ -18,64 +16,72 @@ pub struct Game {
     pub start_date: String,
 }
 
+#[derive(Default)]
 pub struct Gamble {}
 
 impl Gamble {
-    const USER_ID: &'static str = "26485810-436e-4b7f-8eff-6c771d4efdcc";
     const PROMPT: &'static str = "你是一个爬虫助手，把下面文字格式化， 只返回一周内的比赛，直接返回结果，不要添加任何前置回复：";
-    const URL: &'static str = "https://www.aceodds.com/zh-cn/足球/英格兰超级联赛.html";
+    const PATH: &'static str = "/zh-cn/足球/英格兰超级联赛.html";
     pub fn new() -> Self {
-        // let gpt_proxy = Arc::new(GPTProxy::new(
-        //     get_config().model.clone(),
-        //     Gamble::USER_ID.to_string(),
-        //     get_config().gpt_api.clone(),
-        //     get_config().gpt_token.clone(),
-        // ));
         Gamble {}
     }
 }
 
 #[async_trait::async_trait]
 impl Handler for Gamble {
-    async fn on_message(&mut self, msg: &str) -> Result<String> {
-        if msg != "戒赌" {
-            return Err(Error::NotMatchError)?;
-        }
-        let html = reqwest::get(Gamble::URL)
-            .await
-            .map_err(|e| Error::HttpError {
-                status: e.status().unwrap(),
-                url: e.url().unwrap().to_string(),
-                response: e.to_string(),
-            })?
-            .text()
-            .await
-            .map_err(|e| Error::HttpError {
-                status: e.status().unwrap(),
-                url: e.url().unwrap().to_string(),
-                response: e.to_string(),
-            })?;
-        let txt = {
-            let document = Document::from(&html); // Confined to this scope
-            document.select(".table").first().text().to_string()
-        };
-       
+    fn name(&self) -> &str {
+        "gamble"
+    }
+
+    fn matches(&self, msg: &str) -> bool {
+        msg == "戒赌"
+    }
+
+    fn tool(&self) -> Option<Tool> {
+        Some(Tool::new("戒赌", "查询一周内的英超比赛和开球时间"))
+    }
+
+    async fn on_message(&self, ctx: &Context, msg: &str) -> Result<String> {
+        info!("gamble msg: {}", msg);
+        let (config, http) = (&ctx.config, ctx.state.http());
+        let txt = ctx.state.track("aceodds", fetch_table(http, config)).await?;
         let content = format!("{}\n{}", Gamble::PROMPT, txt);
-        let res = gpt::queryGPT(
-            "gpt-4o-mini".to_string(),
-            get_config().gpt_token.clone(),
-            content
-        ).await?;
+        let res = ctx
+            .state
+            .track("llm", gpt::query_gpt(http, config, "gpt-4o-mini".to_string(), content))
+            .await?;
+        ctx.record_usage(self.name(), &res).await;
 
-        Ok(res)
+        Ok(res.content)
     }
 }
 
+async fn fetch_table(http: &reqwest::Client, config: &Config) -> Result<String> {
+    let url = format!("{}{}", config.upstream.aceodds.trim_end_matches('/'), Gamble::PATH);
+    let html = upstream::text(http.get(&url).timeout(config.upstream.timeout("aceodds"))).await?;
+    let txt = {
+        let document = Document::from(&html); // Confined to this scope
+        document.select(".table").first().text().to_string()
+    };
+    Ok(txt)
+}
+
 #[tokio::test]
 async fn test_gamble() {
-    let mut gamble = Gamble::new();
-    match gamble.on_message("戒赌").await {
+    use std::sync::Arc;
+    use crate::{config::ChatConfig, state::BotState};
+    let config = Config::builder().env(std::env::vars()).build().unwrap();
+    let state = Arc::new(BotState::new(config).unwrap());
+    let ctx = state.context(ChatConfig::new("room"), "user").await;
+    let gamble = Gamble::new();
+    match gamble.on_message(&ctx, "戒赌").await {
         Ok(v) => println!("{}", v),
         Err(e) => println!("{:?}", e),
     }
 }
+
+#[tokio::test]
+async fn test_gamble_connection_failure() {
+    let e = fetch_table(&reqwest::Client::new(), &upstream::unreachable_config()).await.unwrap_err();
+    assert!(upstream::is_transport_error(&e), "{e:?}");
+}
@@ -1,24 +1,115 @@
-use log::info;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use log::debug;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
-use reqwest::StatusCode;
-use serde_json::json;
-use tonic::async_trait;
+use anyhow::Result;
+use serde_json::{Value, json};
 
-use crate::{config::get_config, error::Error};
+use crate::config::Config;
+use crate::error::Error;
+use crate::upstream;
+
+/// 接口返回的 token 用量
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+/// 模型要求调用的工具
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// JSON 字符串，原样交给 HandlerMgr::call_tool
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub content: String,
+    pub model: String,
+    pub usage: Usage,
+    /// 不为空时 content 一般为空，需要执行这些工具后再请求一次
+    pub tool_calls: Vec<ToolCall>,
+}
+
+impl Completion {
+    /// 加入对话历史的 assistant 消息
+    pub fn message(&self) -> Value {
+        if self.tool_calls.is_empty() {
+            return json!({"role": "assistant", "content": self.content});
+        }
+        let calls: Vec<Value> = self
+            .tool_calls
+            .iter()
+            .map(|c| json!({"id": c.id, "type": "function", "function": {"name": c.name, "arguments": c.arguments}}))
+            .collect();
+        let content = Some(&self.content).filter(|c| !c.is_empty());
+        json!({"role": "assistant", "content": content, "tool_calls": calls})
+    }
+}
+
+// 取第一个 choice 的内容
+fn parse_content(resp: &Value) -> Result<String> {
+    let content = resp
+        .pointer("/choices/0/message/content")
+        .and_then(|v| v.as_str())
+        .ok_or(Error::ResultError("gpt returned no content"))?;
+    Ok(content.to_string())
+}
+
+fn parse_tool_calls(resp: &Value) -> Vec<ToolCall> {
+    let Some(calls) = resp.pointer("/choices/0/message/tool_calls").and_then(|v| v.as_array()) else {
+        return Vec::new();
+    };
+    calls
+        .iter()
+        .filter_map(|c| {
+            Some(ToolCall {
+                id: c["id"].as_str()?.to_string(),
+                name: c["function"]["name"].as_str()?.to_string(),
+                arguments: c["function"]["arguments"].as_str().unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+// 要求调用工具时可以没有 content
+fn parse_completion(resp: &Value, model: &str) -> Result<Completion> {
+    let tool_calls = parse_tool_calls(resp);
+    let content = match parse_content(resp) {
+        Ok(content) => content,
+        Err(_) if !tool_calls.is_empty() => String::new(),
+        Err(e) => return Err(e),
+    };
+    Ok(Completion {
+        content,
+        model: model.to_string(),
+        usage: parse_usage(resp),
+        tool_calls,
+    })
+}
+
+fn parse_usage(resp: &Value) -> Usage {
+    resp.pointer("/usage")
+        .and_then(|u| serde_json::from_value(u.clone()).ok())
+        .unwrap_or_default()
+}
 
 pub struct GPTProxy {
     model: String,
     user_id: String,
-    client: Arc<Mutex<reqwest::Client>>,
+    client: reqwest::Client,
+    timeout: Option<Duration>,
     url: String,
     token: String,
     pre_set: String,
 }
 
-pub async fn queryGPT(model: String, token: String, content: String) -> Result<String> {
+/// 使用 config 中的 gpt_api 和 gpt_token，不带前置提示词
+pub async fn query_gpt(client: &reqwest::Client, config: &Config, model: String, content: String) -> Result<Completion> {
     let body = json!({
      "model": model,
      "messages" :[{
@@ -26,148 +117,149 @@ pub async fn queryGPT(model: String, token: String, content: String) -> Result<S
          "content":content
      }]
     });
-    let url = "https://api.302.ai/v1/chat/completions";
-    let body_str = serde_json::to_string(&body).unwrap();
-    let client = reqwest::Client::new();
-    let resp = client
-        .post(url)
+    let url = format!("{}{}", config.gpt_api.trim_end_matches('/'), GPTProxy::CHAT_API);
+    let req = client
+        .post(&url)
+        .timeout(config.upstream.timeout("gpt"))
         .header("Content-Type", "application/json")
         .header("Accept", "application/json")
-        .header("Authorization", get_config().gpt_token.clone())
-        .body(body_str)
-        .send()
-        .await
-        .map_err(|e| Error::HttpError {
-            status: e.status().unwrap(),
-            url: e.url().unwrap().to_string(),
-            response: e.to_string(),
-        })?
-        .json::<serde_json::Value>()
-        .await
-        .map_err(|e| Error::JsonError(e.to_string()))?;
-    println!("{}", serde_json::to_string(&resp).unwrap());
-
-    let res: Vec<String> = resp
-        .pointer("/choices")
-        .unwrap()
-        .as_array()
-        .unwrap()
-        .iter()
-        .map(|v| {
-            return v
-                .pointer("/message/content")
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string();
-        })
-        .collect();
-    if res.is_empty() {
-        return Err(Error::HttpError {
-            status: StatusCode::SEE_OTHER,
-            url: url.to_string(),
-            response: String::from("content empty"),
-        })?;
-    }
-    Ok(res.get(0).unwrap().to_string())
+        .header("Authorization", &config.gpt_token)
+        .body(body.to_string());
+    let resp = upstream::json(req).await?;
+    debug!("gpt response: {}", resp);
+
+    Ok(Completion {
+        content: parse_content(&resp)?,
+        model,
+        usage: parse_usage(&resp),
+        tool_calls: Vec::new(),
+    })
 }
 
 impl GPTProxy {
     const CHAT_API: &'static str = "/chat/completions";
     pub fn new(
+        client: reqwest::Client,
         model: String,
         user_id: String,
         url: String,
         token: String,
         pre_set: String,
     ) -> Self {
-        let client = Arc::new(Mutex::new(reqwest::Client::new()));
         GPTProxy {
             model,
             user_id,
             client,
+            timeout: None,
             url: format!("{}{}", url, GPTProxy::CHAT_API).to_string(),
             token,
             pre_set,
         }
     }
 
-    pub async fn test(&mut self) -> Arc<String> {
-        println!("test");
-        Arc::new(String::from("test"))
+    /// 覆盖 client 默认的请求时限
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub async fn query(&self, content: String) -> Result<Completion> {
+        self.complete(&self.messages(&content), &[], false).await
+    }
+
+    /// 对话的第一条消息，带上前置提示词
+    pub fn messages(&self, content: &str) -> Vec<Value> {
+        vec![json!({"role": "user", "content": format!("{}{}", self.pre_set, content)})]
     }
 
-    pub async fn query(&mut self, content: String) -> Result<String> {
-        let final_content = format!("{}{}", self.pre_set, content);
-        let body = json!({
-         "model": self.model.clone(),
-         "messages" :[{
-             "role": "user",
-             "content":final_content
-         }],
-         "userid": self.user_id
+    /// 带上完整的对话历史请求一次；tools 为空时不带工具，call_tools 为 false 时要求模型直接回答
+    pub async fn complete(&self, messages: &[Value], tools: &[Value], call_tools: bool) -> Result<Completion> {
+        let mut body = json!({
+            "model": self.model,
+            "messages": messages,
+            "userid": self.user_id,
         });
-        let body_str = serde_json::to_string(&body).unwrap();
-        let resp = self
-            .client
-            .lock()
-            .await
-            .post(&self.url)
+        if !tools.is_empty() {
+            body["tools"] = json!(tools);
+            if !call_tools {
+                body["tool_choice"] = json!("none");
+            }
+        }
+        let mut req = self.client.post(&self.url);
+        if let Some(timeout) = self.timeout {
+            req = req.timeout(timeout);
+        }
+        let req = req
             .header("Content-Type", "application/json")
             .header("Accept", "application/json")
             .header("Authorization", &self.token)
-            .body(body_str)
-            .send()
-            .await
-            .map_err(|e| Error::HttpError {
-                status: e.status().unwrap(),
-                url: e.url().unwrap().to_string(),
-                response: e.to_string(),
-            })?
-            .json::<serde_json::Value>()
-            .await
-            .map_err(|e| Error::JsonError(e.to_string()))?;
-        info!("{}", serde_json::to_string(&resp).unwrap());
-
-        let res: Vec<String> = resp
-            .pointer("/choices")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|v| {
-                return v
-                    .pointer("/message/content")
-                    .unwrap()
-                    .as_str()
-                    .unwrap()
-                    .to_string();
-            })
-            .collect();
-        if res.is_empty() {
-            return Err(Error::HttpError {
-                status: StatusCode::SEE_OTHER,
-                url: self.url.clone(),
-                response: String::from("content empty"),
-            })?;
-        }
-        let mut res = res.get(0).unwrap().to_string();
-        res = res.trim_start().to_string();
-        Ok(res)
+            .body(body.to_string());
+        let resp = upstream::json(req).await?;
+        debug!("gpt response: {}", resp);
+
+        let mut completion = parse_completion(&resp, &self.model)?;
+        completion.content = completion.content.trim_start().to_string();
+        Ok(completion)
     }
 }
 
 #[tokio::test]
 async fn test_gpt() {
-    let mut gpt_proxy = GPTProxy::new(
-        get_config().model.clone(),
-        get_config().user_id.clone(),
-        get_config().gpt_api.clone(),
-        get_config().gpt_token.clone(),
+    let config = crate::config::Config::builder().env(std::env::vars()).build().unwrap();
+    let gpt_proxy = GPTProxy::new(
+        reqwest::Client::new(),
+        config.model.clone(),
+        config.user_id.clone(),
+        config.gpt_api.clone(),
+        config.gpt_token.clone(),
         String::from(""),
     );
     match gpt_proxy.query(String::from("你好")).await {
-        Ok(v) => println!("{}", v),
+        Ok(v) => println!("{} {:?}", v.content, v.usage),
         Err(e) => println!("{:?}", e),
     }
 }
+
+#[test]
+fn test_parse_usage() {
+    let resp = json!({"usage": {"prompt_tokens": 12, "completion_tokens": 34, "total_tokens": 46}});
+    let usage = parse_usage(&resp);
+    assert_eq!(usage.prompt_tokens, 12);
+    assert_eq!(usage.completion_tokens, 34);
+    assert_eq!(parse_usage(&json!({})).total_tokens, 0);
+}
+
+#[tokio::test]
+async fn test_gpt_connection_failure() {
+    let config = upstream::unreachable_config();
+    let client = reqwest::Client::new();
+    let e = query_gpt(&client, &config, config.model.clone(), String::from("你好")).await.unwrap_err();
+    assert!(upstream::is_transport_error(&e), "{e:?}");
+    let gpt_proxy = GPTProxy::new(client, config.model.clone(), String::new(), config.gpt_api.clone(), String::new(), String::new());
+    let e = gpt_proxy.query(String::from("你好")).await.unwrap_err();
+    assert!(upstream::is_transport_error(&e), "{e:?}");
+
+    assert_eq!(parse_content(&json!({"choices": [{"message": {"content": "hi"}}]})).unwrap(), "hi");
+    assert!(parse_content(&json!({"choices": []})).is_err());
+    assert!(parse_content(&json!({"error": {"message": "bad key"}})).is_err());
+}
+
+#[test]
+fn test_parse_tool_calls() {
+    let call = json!({"id": "call_1", "type": "function", "function": {"name": "market", "arguments": "{}"}});
+    let resp = json!({"choices": [{"message": {"role": "assistant", "content": null, "tool_calls": [call]}}]});
+    let completion = parse_completion(&resp, "m").unwrap();
+    assert_eq!(completion.content, "");
+    assert_eq!(
+        completion.tool_calls,
+        vec![ToolCall {
+            id: String::from("call_1"),
+            name: String::from("market"),
+            arguments: String::from("{}"),
+        }]
+    );
+    let message = completion.message();
+    assert_eq!(message["content"], Value::Null);
+    assert_eq!(message["tool_calls"][0], call);
+    assert!(parse_completion(&json!({"choices": [{"message": {}}]}), "m").is_err());
+}
@@ -0,0 +1,114 @@
+use std::fs;
+use std::sync::Arc;
+
+use anyhow::{Context as _, Result};
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+
+use crate::config::TlsConfig;
+use crate::dispatch::Dispatcher;
+use crate::proxy::proxy_server::Proxy;
+use crate::proxy::{Message, MessageResp};
+
+/// gRPC 的 OnMessage，直接交给 Dispatcher 处理
+pub struct ProxyService {
+    dispatcher: Arc<Dispatcher>,
+}
+
+impl ProxyService {
+    pub fn new(dispatcher: Arc<Dispatcher>) -> Self {
+        ProxyService { dispatcher }
+    }
+}
+
+#[tonic::async_trait]
+impl Proxy for ProxyService {
+    async fn on_message(&self, req: Request<Message>) -> Result<Response<MessageResp>, Status> {
+        Ok(Response::new(self.dispatcher.handle(req.into_inner()).await))
+    }
+}
+
+/// 校验客户端带上的密钥，支持 authorization: Bearer <token> 和 x-bot-token: <token>
+#[derive(Clone)]
+pub struct AuthInterceptor {
+    token: Option<String>,
+}
+
+impl AuthInterceptor {
+    /// token 为空时放行所有请求
+    pub fn new(token: Option<String>) -> Self {
+        AuthInterceptor {
+            token: token.filter(|t| !t.is_empty()),
+        }
+    }
+
+    /// 分别传入 authorization 和 x-bot-token 的值，失败时返回原因
+    pub fn check(&self, authorization: Option<&str>, x_bot_token: Option<&str>) -> Result<(), &'static str> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        let provided = authorization.and_then(|v| v.strip_prefix("Bearer ")).or(x_bot_token);
+        match provided {
+            Some(provided) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => Ok(()),
+            Some(_) => Err("invalid token"),
+            None => Err("missing token"),
+        }
+    }
+}
+
+// 逐字节比较全部内容，避免按耗时猜出密钥
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, req: Request<()>) -> Result<Request<()>, Status> {
+        let metadata = req.metadata();
+        let value = |key: &str| metadata.get(key).and_then(|v| v.to_str().ok());
+        self.check(value("authorization"), value("x-bot-token"))
+            .map_err(Status::unauthenticated)?;
+        Ok(req)
+    }
+}
+
+/// 读取证书生成 tonic 的 TLS 配置，配置了 client_ca 时开启 mTLS
+pub fn tls_config(config: &TlsConfig) -> Result<ServerTlsConfig> {
+    let read = |path: &std::path::Path| fs::read(path).with_context(|| format!("failed to read {}", path.display()));
+    let identity = Identity::from_pem(read(&config.cert)?, read(&config.key)?);
+    let mut tls = ServerTlsConfig::new().identity(identity);
+    if let Some(ca) = &config.client_ca {
+        tls = tls.client_ca_root(Certificate::from_pem(read(ca)?));
+    }
+    Ok(tls)
+}
+
+#[test]
+fn test_auth_interceptor() {
+    let req = |key: &str, value: &str| {
+        let mut req = Request::new(());
+        req.metadata_mut().insert(
+            tonic::metadata::AsciiMetadataKey::from_bytes(key.as_bytes()).unwrap(),
+            value.parse().unwrap(),
+        );
+        req
+    };
+
+    let mut open = AuthInterceptor::new(Some(String::new()));
+    assert!(open.call(Request::new(())).is_ok());
+
+    let mut auth = AuthInterceptor::new(Some(String::from("s3cret")));
+    assert!(auth.call(req("authorization", "Bearer s3cret")).is_ok());
+    assert!(auth.call(req("x-bot-token", "s3cret")).is_ok());
+    let err = auth.call(req("authorization", "Bearer wrong")).unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unauthenticated);
+    assert!(auth.call(req("authorization", "s3cret")).is_err());
+    assert!(auth.call(Request::new(())).is_err());
+
+    let missing = TlsConfig {
+        cert: "/nonexistent/cert.pem".into(),
+        key: "/nonexistent/key.pem".into(),
+        client_ca: None,
+    };
+    assert!(tls_config(&missing).is_err());
+}
@@ -1,19 +1,247 @@
 use std::sync::Arc;
-use log::info;
-use tokio::sync::Mutex;
+use std::time::Instant;
+use chrono::Local;
+use log::{error, info, warn};
+use regex::Regex;
 
 use anyhow::Result;
+use serde_json::{Value, json};
 
+use crate::admin;
+use crate::basic_market_info::BasicMakertInfo;
+use crate::gamble::Gamble;
+use crate::help::Help;
+use crate::huangli::HuangLi;
+use crate::config::{ChatConfig, Config};
 use crate::error::Error;
+use crate::gpt::Completion;
+use crate::metrics;
+use crate::state::{BotState, ChatOverride};
+use crate::storage::Storage;
+use crate::usage::{UsageRecord, month_range};
 
+/// 一条消息的处理上下文
+pub struct Context {
+    pub config: Arc<Config>,
+    pub state: Arc<BotState>,
+    pub chat: ChatConfig,
+    pub sender_id: String,
+    pub overrides: ChatOverride,
+}
+
+impl Context {
+    /// 综合配置和管理员运行时开关判断指令是否可用
+    pub fn command_enabled(&self, name: &str) -> bool {
+        if self.overrides.disabled.contains(name) {
+            return false;
+        }
+        self.overrides.enabled.contains(name) || self.chat.command_enabled(name)
+    }
+
+    pub fn model(&self) -> &str {
+        self.overrides
+            .model
+            .as_deref()
+            .unwrap_or_else(|| self.config.model_for(&self.chat))
+    }
+
+    pub fn preset(&self) -> &str {
+        self.overrides
+            .preset
+            .as_deref()
+            .unwrap_or_else(|| self.config.preset_for(&self.chat))
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.config.admins.contains(&self.sender_id)
+    }
+
+    /// 按用户、会话、指令检查限流，超限时返回 Error::RateLimited
+    pub async fn check_rate_limit(&self, command: &str) -> Result<()> {
+        self.state
+            .limiter()
+            .check(&self.config.rate_limit, &self.chat.id, &self.sender_id, command)
+            .await
+            .map_err(|wait| {
+                self.state.metrics().observe_rate_limited(command);
+                Error::RateLimited(wait.as_secs().max(1))
+            })?;
+        Ok(())
+    }
+
+    /// 记录一次大模型调用的用量，失败只打日志
+    pub async fn record_usage(&self, handler: &str, completion: &Completion) {
+        self.state.metrics().observe_tokens(&completion.model, &completion.usage);
+        if self.config.usage.price(&completion.model).is_none() {
+            warn!("model {} has no usage.pricing, its cost is recorded as 0", completion.model);
+        }
+        let record = UsageRecord::new(&self.config.usage, &self.chat.id, &self.sender_id, handler, completion);
+        if let Err(e) = self.storage().call(move |s| s.usage().insert(&record)).await {
+            error!("failed to record usage, err: {:?}", e);
+        }
+    }
+
+    /// 本月费用是否已超出预算
+    pub async fn budget_exceeded(&self) -> bool {
+        let Some(budget) = self.config.usage.monthly_budget else {
+            return false;
+        };
+        let (from, to) = month_range(Local::now().date_naive());
+        match self.storage().call(move |s| s.usage().cost(from, to)).await {
+            Ok(cost) => cost >= budget,
+            Err(e) => {
+                error!("failed to get usage cost, err: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// 自由文本触发需要指令本身可用，并且在该会话中开启了触发
+    pub fn trigger_enabled(&self, name: &str) -> bool {
+        self.command_enabled(name) && self.chat.trigger_enabled(name)
+    }
+
+    pub fn storage(&self) -> &Storage {
+        self.state.storage()
+    }
+
+    /// 按会话、指令和结果统计请求数
+    pub fn observe_request<T>(&self, command: &str, result: &Result<T>) {
+        self.state
+            .metrics()
+            .observe_request(&self.chat.id, command, metrics::outcome(result));
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.overrides
+            .muted_until
+            .is_some_and(|until| until > Local::now())
+    }
+}
+
+pub enum Pattern {
+    /// 包含任意一个关键词
+    Keywords(Vec<String>),
+    Regex(Regex),
+}
+
+/// 不以 / 开头的消息的触发条件
+pub struct Trigger {
+    pub pattern: Pattern,
+    /// 多个触发条件都命中时取优先级最高的，相同时按注册顺序
+    pub priority: i32,
+}
+
+impl Trigger {
+    pub fn keywords(keywords: &[&str], priority: i32) -> Self {
+        Trigger {
+            pattern: Pattern::Keywords(keywords.iter().map(|k| k.to_string()).collect()),
+            priority,
+        }
+    }
+
+    pub fn regex(pattern: &str, priority: i32) -> Result<Self> {
+        let regex = Regex::new(pattern).map_err(|e| Error::ConfigError(e.to_string()))?;
+        Ok(Trigger {
+            pattern: Pattern::Regex(regex),
+            priority,
+        })
+    }
+
+    pub fn is_match(&self, msg: &str) -> bool {
+        match &self.pattern {
+            Pattern::Keywords(keywords) => keywords.iter().any(|k| msg.contains(k.as_str())),
+            Pattern::Regex(regex) => regex.is_match(msg),
+        }
+    }
+}
+
+/// 指令作为大模型工具（function calling）时的说明
+pub struct Tool {
+    /// 告诉模型这个工具做什么、什么时候调用
+    pub description: String,
+    /// 调用时传给 on_message 的指令，即去掉 / 的指令本身
+    pub command: String,
+    /// 指令参数的说明，不需要参数时为 None；模型给出的参数拼在 command 之后
+    pub args: Option<String>,
+}
+
+impl Tool {
+    pub fn new(command: &str, description: &str) -> Self {
+        Tool {
+            description: description.to_string(),
+            command: command.to_string(),
+            args: None,
+        }
+    }
+
+    pub fn args(mut self, description: &str) -> Self {
+        self.args = Some(description.to_string());
+        self
+    }
+
+    /// OpenAI 格式的工具定义，name 为指令名
+    pub fn schema(&self, name: &str) -> Value {
+        let (properties, required) = match &self.args {
+            Some(args) => (json!({"args": {"type": "string", "description": args}}), json!(["args"])),
+            None => (json!({}), json!([])),
+        };
+        json!({
+            "type": "function",
+            "function": {
+                "name": name,
+                "description": self.description,
+                "parameters": {"type": "object", "properties": properties, "required": required},
+            },
+        })
+    }
+
+    // 模型给出的参数为 {"args": "..."}
+    fn message(&self, arguments: &str) -> Result<String> {
+        let arguments: Value = match arguments.trim() {
+            "" => json!({}),
+            a => serde_json::from_str(a).map_err(|e| Error::ParamError(format!("invalid tool arguments: {e}")))?,
+        };
+        Ok(match arguments["args"].as_str().map(str::trim).filter(|a| !a.is_empty()) {
+            Some(args) => format!("{} {}", self.command, args),
+            None => self.command.clone(),
+        })
+    }
+}
+
+// 模型接口要求工具名只包含字母、数字、_ 和 -
+fn is_tool_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// 消息处理器，会被多条消息同时调用，需要保存的状态自行加锁
 #[async_trait::async_trait]
 pub trait Handler : Send + Sync{
-    async fn on_message(&mut self, msg: &str) -> Result<String>;
+    /// 指令名，用于按群开关指令和限流
+    fn name(&self) -> &str;
+
+    /// 是否处理这条指令（已去掉开头的 /）
+    fn matches(&self, msg: &str) -> bool;
+
+    /// 自由文本触发条件，命中时以完整消息调用 on_message
+    fn triggers(&self) -> Vec<Trigger> {
+        Vec::new()
+    }
+
+    /// 作为工具交给 GPT 兜底回复调用，不提供时模型看不到这个指令
+    fn tool(&self) -> Option<Tool> {
+        None
+    }
+
+    async fn on_message(&self, ctx: &Context, msg: &str) -> Result<String>;
 }
 
+/// 启动时注册所有处理器，之后只读，可以放进 Arc 在多条消息间共享
 #[derive(Default)]
 pub struct HandlerMgr {
-    handlers: Arc<Mutex<Vec<Arc<Mutex<dyn Handler>>>>>,
+    handlers: Vec<Box<dyn Handler>>,
+    // (触发条件, handlers 下标)，按优先级从高到低
+    triggers: Vec<(Trigger, usize)>,
 }
 
 impl HandlerMgr {
@@ -21,33 +249,280 @@ impl HandlerMgr {
        Self::default()
     }
 
-    pub async fn register_handler(&mut self, handler: Arc<Mutex<dyn Handler>>) {
-        let mut handlers = self
+    /// 注册全部内置处理器
+    pub fn builtin() -> Self {
+        let mut handlers = HandlerMgr::new();
+        handlers.register_handler(BasicMakertInfo::new());
+        handlers.register_handler(Gamble::new());
+        handlers.register_handler(HuangLi::new());
+        handlers.register_handler(Help::new());
+        handlers
+    }
+
+    pub fn register_handler(&mut self, handler: impl Handler + 'static) {
+        let index = self.handlers.len();
+        self.triggers.extend(handler.triggers().into_iter().map(|t| (t, index)));
+        self.triggers.sort_by_key(|(t, _)| std::cmp::Reverse(t.priority));
+        self.handlers.push(Box::new(handler));
+    }
+
+    pub fn handler_names(&self) -> Vec<&str> {
+        self.handlers.iter().map(|h| h.name()).collect()
+    }
+
+    /// 没有可用的处理器匹配这条指令时返回 None
+    pub async fn match_handler(&self, ctx: &Context, msg: &str) -> Result<Option<String>> {
+        if ctx.is_admin()
+            && let Some(args) = admin::strip_prompt(msg)
+        {
+            tracing::Span::current().record("command", "admin");
+            let res = admin::on_admin(ctx, self, args).await;
+            ctx.observe_request("admin", &res);
+            return res.map(Some);
+        }
+        info!("try match msg: {}, handler size: {}", msg, self.handlers.len());
+        let Some(h) = self
             .handlers
-            .lock()
-            .await;
-            // .expect("Failed to acquire handlers lock");
-        handlers.push(handler);
+            .iter()
+            .find(|h| ctx.command_enabled(h.name()) && h.matches(msg))
+        else {
+            return Ok(None);
+        };
+
+        self.run(h.as_ref(), ctx, msg).await.map(Some)
+    }
+
+    /// 按优先级匹配自由文本触发，没有命中时返回 None
+    pub async fn match_trigger(&self, ctx: &Context, msg: &str) -> Result<Option<String>> {
+        let Some((_, index)) = self
+            .triggers
+            .iter()
+            .find(|(t, i)| ctx.trigger_enabled(self.handlers[*i].name()) && t.is_match(msg))
+        else {
+            return Ok(None);
+        };
+        let h = self.handlers[*index].as_ref();
+        info!("trigger {} by msg: {}", h.name(), msg);
+        self.run(h, ctx, msg).await.map(Some)
+    }
+
+    /// 本会话可用的指令中提供了 tool 的，按 OpenAI 格式返回工具定义
+    pub fn tools(&self, ctx: &Context) -> Vec<Value> {
+        self.handlers
+            .iter()
+            .filter(|h| ctx.command_enabled(h.name()) && is_tool_name(h.name()))
+            .filter_map(|h| h.tool().map(|t| t.schema(h.name())))
+            .collect()
     }
 
-    pub async fn match_handler(&self, msg: &str) -> Result<String> {
-        let handlers = self
+    /// 执行模型要求的工具调用，和 / 指令一样限流和统计
+    pub async fn call_tool(&self, ctx: &Context, name: &str, arguments: &str) -> Result<String> {
+        let (h, tool) = self
             .handlers
-            .lock()
-            .await;
-        info!("try match msg: {}, handler size: {}", msg, handlers.len());
-        for h in handlers.iter() {
-            let mut h = h.lock().await;
-            match h.on_message(msg).await {
-                Ok(v) => return Ok(v),
-                Err(e) => {
-                    if e.to_string() == Error::NotMatchError.to_string() {
-                        continue;
-                    }
-                    return Err(e);
-                },
-            }
+            .iter()
+            .filter(|h| h.name() == name && ctx.command_enabled(name))
+            .find_map(|h| h.tool().map(|t| (h, t)))
+            .ok_or_else(|| Error::ParamError(format!("unknown tool {name}")))?;
+        let msg = tool.message(arguments)?;
+        info!("tool {} called with msg: {}", name, msg);
+        self.run(h.as_ref(), ctx, &msg).await
+    }
+
+    async fn run(&self, h: &dyn Handler, ctx: &Context, msg: &str) -> Result<String> {
+        tracing::Span::current().record("command", h.name());
+        if let Err(e) = ctx.check_rate_limit(h.name()).await {
+            let res = Err(e);
+            ctx.observe_request(h.name(), &res);
+            return res;
+        }
+        let start = Instant::now();
+        let res = h.on_message(ctx, msg).await;
+        let elapsed = start.elapsed();
+        ctx.state.metrics().observe_handler(h.name(), elapsed);
+        ctx.observe_request(h.name(), &res);
+        ctx.state.record_handler(h.name(), res.is_ok(), elapsed.as_millis()).await;
+        res
+    }
+}
+
+#[tokio::test]
+async fn test_match_handler_per_chat() {
+    use crate::help::Help;
+    let mut mgr = HandlerMgr::new();
+    mgr.register_handler(Help::new());
+    let config = Config::builder()
+        .set("room_id", "all")
+        .set("rate_limit.commands", r#"{"help": {"capacity": 1, "per_minute": 1}}"#)
+        .build()
+        .unwrap();
+    let state = Arc::new(BotState::new(config).unwrap());
+
+    let all = state.context(ChatConfig::new("all"), "user").await;
+    assert!(mgr.match_handler(&all, "help").await.unwrap().is_some());
+    assert!(mgr.match_handler(&all, "unknown").await.unwrap().is_none());
+    let err = mgr.match_handler(&all, "help").await.unwrap_err();
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::RateLimited(_))));
+
+    let mut none = ChatConfig::new("none");
+    none.commands = Some(vec![]);
+    let none = state.context(none, "user").await;
+    assert!(mgr.match_handler(&none, "help").await.unwrap().is_none());
+
+    let stats = state.handler_stats().await;
+    assert_eq!(stats[0].0, "help");
+    assert_eq!(stats[0].1.calls, 1);
+}
+
+#[tokio::test]
+async fn test_handlers_run_concurrently() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Slow {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Handler for Slow {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        fn matches(&self, msg: &str) -> bool {
+            msg == "slow"
+        }
+
+        async fn on_message(&self, _ctx: &Context, _msg: &str) -> Result<String> {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(self.calls.fetch_add(1, Ordering::SeqCst).to_string())
         }
-        Err(Error::NotMatchError)?
     }
+
+    let mut mgr = HandlerMgr::new();
+    mgr.register_handler(Slow::default());
+    let mgr = Arc::new(mgr);
+    let state = Arc::new(BotState::new(Config::builder().set("room_id", "a").build().unwrap()).unwrap());
+    let a = state.context(ChatConfig::new("a"), "user").await;
+    let b = state.context(ChatConfig::new("b"), "user").await;
+
+    let start = Instant::now();
+    let (ra, rb) = tokio::join!(mgr.match_handler(&a, "slow"), mgr.match_handler(&b, "slow"));
+    assert!(start.elapsed() < Duration::from_millis(390));
+    let mut replies = vec![ra.unwrap().unwrap(), rb.unwrap().unwrap()];
+    replies.sort();
+    assert_eq!(replies, vec!["0", "1"]);
+}
+
+#[tokio::test]
+async fn test_match_trigger() {
+    struct Echo {
+        name: &'static str,
+        triggers: fn() -> Vec<Trigger>,
+    }
+
+    #[async_trait::async_trait]
+    impl Handler for Echo {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn matches(&self, msg: &str) -> bool {
+            msg == self.name
+        }
+
+        fn triggers(&self) -> Vec<Trigger> {
+            (self.triggers)()
+        }
+
+        async fn on_message(&self, _ctx: &Context, msg: &str) -> Result<String> {
+            Ok(format!("{}: {}", self.name, msg))
+        }
+    }
+
+    let mut mgr = HandlerMgr::new();
+    mgr.register_handler(Echo {
+        name: "quote",
+        triggers: || vec![Trigger::regex(r"(?i)btc.*多少", 0).unwrap()],
+    });
+    mgr.register_handler(Echo {
+        name: "link",
+        triggers: || vec![Trigger::keywords(&["http://", "https://"], 10)],
+    });
+    let state = Arc::new(BotState::new(Config::builder().set("room_id", "a").build().unwrap()).unwrap());
+
+    // 没有配置 triggers 时不触发
+    let ctx = state.context(ChatConfig::new("a"), "user").await;
+    assert!(mgr.match_trigger(&ctx, "BTC多少了").await.unwrap().is_none());
+
+    let mut chat = ChatConfig::new("a");
+    chat.triggers = Some(vec![String::from("quote"), String::from("link")]);
+    let ctx = state.context(chat, "user").await;
+    assert_eq!(mgr.match_trigger(&ctx, "BTC多少了").await.unwrap().unwrap(), "quote: BTC多少了");
+    assert!(mgr.match_trigger(&ctx, "今天天气").await.unwrap().is_none());
+    // 两个都命中时优先级高的先触发
+    let reply = mgr.match_trigger(&ctx, "btc多少 https://x.com").await.unwrap().unwrap();
+    assert!(reply.starts_with("link"));
+
+    let mut chat = ChatConfig::new("b");
+    chat.triggers = Some(vec![String::from("quote")]);
+    let ctx = state.context(chat, "user").await;
+    assert!(mgr.match_trigger(&ctx, "https://x.com").await.unwrap().is_none());
+    let reply = mgr.match_trigger(&ctx, "btc多少 https://x.com").await.unwrap().unwrap();
+    assert!(reply.starts_with("quote"));
+
+    // 指令被关闭时触发也失效
+    let mut chat = ChatConfig::new("c");
+    chat.commands = Some(vec![String::from("link")]);
+    chat.triggers = Some(vec![String::from("quote"), String::from("link")]);
+    let ctx = state.context(chat, "user").await;
+    assert!(mgr.match_trigger(&ctx, "BTC多少了").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_tools() {
+    struct Quote;
+
+    #[async_trait::async_trait]
+    impl Handler for Quote {
+        fn name(&self) -> &str {
+            "quote"
+        }
+
+        fn matches(&self, msg: &str) -> bool {
+            msg.starts_with("行情")
+        }
+
+        fn tool(&self) -> Option<Tool> {
+            Some(Tool::new("行情", "查询行情").args("币种，例如 BTC"))
+        }
+
+        async fn on_message(&self, _ctx: &Context, msg: &str) -> Result<String> {
+            Ok(format!("quote: {msg}"))
+        }
+    }
+
+    let mut mgr = HandlerMgr::new();
+    mgr.register_handler(Quote);
+    mgr.register_handler(crate::help::Help::new());
+    let state = Arc::new(BotState::new(Config::builder().set("room_id", "a").build().unwrap()).unwrap());
+    let ctx = state.context(ChatConfig::new("a"), "user").await;
+
+    let tools = mgr.tools(&ctx);
+    assert_eq!(tools.len(), 1);
+    assert_eq!(tools[0]["function"]["name"], "quote");
+    assert_eq!(tools[0]["function"]["parameters"]["required"], json!(["args"]));
+    assert_eq!(mgr.call_tool(&ctx, "quote", r#"{"args": " BTC "}"#).await.unwrap(), "quote: 行情 BTC");
+    assert_eq!(mgr.call_tool(&ctx, "quote", "").await.unwrap(), "quote: 行情");
+    assert!(mgr.call_tool(&ctx, "quote", "not json").await.is_err());
+    assert!(mgr.call_tool(&ctx, "help", "{}").await.is_err());
+    assert_eq!(state.handler_stats().await[0].1.calls, 2);
+
+    // 关闭的指令不作为工具
+    let mut chat = ChatConfig::new("b");
+    chat.commands = Some(vec![String::from("help")]);
+    let ctx = state.context(chat, "user").await;
+    assert!(mgr.tools(&ctx).is_empty());
+    assert!(mgr.call_tool(&ctx, "quote", "{}").await.is_err());
+    assert!(!is_tool_name("牛回"));
 }
@@ -1,9 +1,9 @@
 use anyhow::Result;
 use log::info;
 
-use crate::error::Error;
-use crate::handler::Handler;
+use crate::handler::{Context, Handler};
 
+#[derive(Default)]
 pub struct Help {}
 
 impl Help {
@@ -16,12 +16,17 @@ impl Help {
 
 #[async_trait::async_trait]
 impl Handler for Help {
-    async fn on_message(&mut self, msg: &str) -> Result<String> {
+    fn name(&self) -> &str {
+        "help"
+    }
+
+    fn matches(&self, msg: &str) -> bool {
+        msg == Help::PROMPT
+    }
+
+    async fn on_message(&self, _ctx: &Context, msg: &str) -> Result<String> {
         info!("help msg: {}", msg);
-        if msg != Help::PROMPT {
-            return Err(Error::NotMatchError)?;
-        }
-        
+
         let help_text = r#"可用指令列表：
 /help - 显示此帮助信息
 /牛回 或 /牛死 - 炒股biss
@@ -36,8 +41,12 @@ impl Handler for Help {
 
 #[tokio::test]
 async fn test_help() {
-    let mut help = Help::new();
-    match help.on_message("help").await {
+    use std::sync::Arc;
+    use crate::{config::{ChatConfig, Config}, state::BotState};
+    let state = Arc::new(BotState::new(Config::default()).unwrap());
+    let ctx = state.context(ChatConfig::new("room"), "user").await;
+    let help = Help::new();
+    match help.on_message(&ctx, "help").await {
         Ok(v) => println!("{}", v),
         Err(e) => println!("{:?}", e),
     }
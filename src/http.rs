@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::json;
+
+use crate::config::HttpConfig;
+use crate::dispatch::Dispatcher;
+use crate::grpc::AuthInterceptor;
+use crate::proxy::Message;
+
+#[derive(Clone)]
+struct HttpState {
+    dispatcher: Arc<Dispatcher>,
+    auth: AuthInterceptor,
+}
+
+/// http 服务的路由：/metrics 和 POST /message，和 gRPC 共用同一个 Dispatcher
+pub fn router(dispatcher: Arc<Dispatcher>, config: &HttpConfig, auth_token: Option<String>) -> Router {
+    let mut router = Router::new();
+    if config.metrics {
+        router = router.route("/metrics", get(metrics));
+    }
+    if config.message {
+        router = router.route("/message", post(on_message));
+    }
+    router.with_state(HttpState {
+        dispatcher,
+        auth: AuthInterceptor::new(auth_token),
+    })
+}
+
+async fn metrics(State(state): State<HttpState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.dispatcher.state().metrics().render(),
+    )
+}
+
+/// 请求体是 proto3 json 形式的 Message，返回 {"code": "Ok", "response": "..."}
+async fn on_message(State(state): State<HttpState>, headers: HeaderMap, Json(message): Json<Message>) -> Response {
+    let value = |key: &str| headers.get(key).and_then(|v| v.to_str().ok());
+    if let Err(e) = state.auth.check(value("authorization"), value("x-bot-token")) {
+        return (StatusCode::UNAUTHORIZED, e).into_response();
+    }
+    let resp = state.dispatcher.handle(message).await;
+    Json(json!({
+        "code": resp.code().as_str_name(),
+        "response": resp.response,
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+use crate::testing::serve;
+
+#[cfg(test)]
+fn dispatcher() -> Arc<Dispatcher> {
+    use crate::config::Config;
+    use crate::handler::HandlerMgr;
+    use crate::state::BotState;
+
+    let config = Config::builder().set("room_id", "room").build().unwrap();
+    let mut handlers = HandlerMgr::new();
+    handlers.register_handler(crate::help::Help::new());
+    Arc::new(Dispatcher::new(Arc::new(BotState::new(config).unwrap()), handlers, 4))
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    use crate::config::ChatConfig;
+
+    let dispatcher = dispatcher();
+    let ctx = dispatcher.state().context(ChatConfig::new("room"), "alice").await;
+    ctx.observe_request("help", &Ok(()));
+
+    let addr = serve(router(dispatcher.clone(), &HttpConfig::default(), None)).await;
+    let resp = reqwest::get(format!("http://{addr}/metrics")).await.unwrap();
+    assert!(resp.status().is_success());
+    let body = resp.text().await.unwrap();
+    assert!(body.contains(r#"wechat_bot_requests_total{command="help",outcome="ok",room="room"} 1"#));
+    // 默认不提供 /message
+    let resp = reqwest::Client::new().post(format!("http://{addr}/message")).send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let disabled = HttpConfig {
+        metrics: false,
+        ..Default::default()
+    };
+    let addr = serve(router(dispatcher, &disabled, None)).await;
+    let resp = reqwest::get(format!("http://{addr}/metrics")).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_message_endpoint() {
+    let config = HttpConfig {
+        message: true,
+        ..Default::default()
+    };
+    let addr = serve(router(dispatcher(), &config, Some(String::from("s3cret")))).await;
+    let url = format!("http://{addr}/message");
+    let client = reqwest::Client::new();
+    let body = json!({"isRoom": true, "isMemtioned": true, "roomId": "room", "senderId": "alice", "content": "/help"});
+
+    let resp = client.post(&url).json(&body).send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let resp = client.post(&url).bearer_auth("s3cret").json(&body).send().await.unwrap();
+    assert!(resp.status().is_success());
+    let resp: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(resp["code"], "Ok");
+    assert!(resp["response"].as_str().unwrap().starts_with("可用指令列表"));
+
+    // 缺少的字段按默认值处理
+    let resp = client
+        .post(&url)
+        .header("x-bot-token", "s3cret")
+        .json(&json!({"isRoom": true, "roomId": "other", "content": "/help"}))
+        .send()
+        .await
+        .unwrap();
+    let resp: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(resp["code"], "Ignore");
+
+    let resp = client.post(&url).bearer_auth("s3cret").body("not json").send().await.unwrap();
+    assert!(resp.status().is_client_error());
+}
@@ -2,65 +2,78 @@ use anyhow::Result;
 use chrono::Local;
 use log::info;
 
-use crate::{config::get_config, error::Error, handler::Handler};
+use crate::{config::Config, error::Error, handler::{Context, Handler, Tool}, upstream};
 
-pub struct HuangLi {
-    api_key: String,
-}
+#[derive(Default)]
+pub struct HuangLi {}
 
 impl HuangLi {
-    const prompt: &'static str = "算命";
-    const url: &'static str = "http://v.juhe.cn/laohuangli/d";
+    const PROMPT: &'static str = "算命";
+    const PATH: &'static str = "/laohuangli/d";
     pub fn new() -> Self {
-        HuangLi {
-            api_key: get_config().huangli_apikey.clone(),
-        }
+        HuangLi {}
     }
 }
 
 #[async_trait::async_trait]
 impl Handler for HuangLi {
-    async fn on_message(&mut self, msg: &str) -> Result<String> {
-        info!("gamble msg: {}", msg);
-        if msg != HuangLi::prompt {
-            Err(Error::NotMatchError)?
-        }
-        let now = Local::now().format("%Y-%m-%d").to_string();
-        let url = format!("{}?date={}&key={}", HuangLi::url, &now, &self.api_key);
-        let resp = reqwest::get(&url)
-            .await
-            .map_err(|e| Error::HttpError {
-                status: e.status().unwrap(),
-                url: e.url().unwrap().to_string(),
-                response: e.to_string(),
-            })?
-            .json::<serde_json::Value>()
-            .await
-            .map_err(|e| Error::JsonError(String::from("failed to parse to json value")))?
-            .pointer("/result")
-            .ok_or(Error::ResultError("no result"))?
-            .clone();
+    fn name(&self) -> &str {
+        "huangli"
+    }
+
+    fn matches(&self, msg: &str) -> bool {
+        msg == HuangLi::PROMPT
+    }
 
-        let yi = resp
-            .pointer("/yi")
-            .ok_or(Error::ResultError("invalid result for yi"))?
-            .as_str()
-            .ok_or(Error::ResultError("parse failed for yi"))?;
-        let ji = resp
-            .pointer("/ji")
-            .ok_or(Error::ResultError("invalid result for ji"))?
-            .as_str()
-            .ok_or(Error::ResultError("parse failed for ji"))?;
+    fn tool(&self) -> Option<Tool> {
+        Some(Tool::new(HuangLi::PROMPT, "查询今天老黄历的宜和忌"))
+    }
 
-        Ok(format!("宜：{}\n忌: {}", yi, ji))
+    async fn on_message(&self, ctx: &Context, msg: &str) -> Result<String> {
+        info!("huangli msg: {}", msg);
+        ctx.state.track("juhe", get_huangli(ctx.state.http(), &ctx.config)).await
     }
 }
 
+async fn get_huangli(http: &reqwest::Client, config: &Config) -> Result<String> {
+    let now = Local::now().format("%Y-%m-%d").to_string();
+    let base = config.upstream.juhe.trim_end_matches('/');
+    let url = format!("{}{}?date={}&key={}", base, HuangLi::PATH, &now, config.huangli_apikey);
+    let resp = upstream::json(http.get(&url).timeout(config.upstream.timeout("juhe"))).await?;
+    let resp = resp.pointer("/result").ok_or(Error::ResultError("no result"))?;
+
+    let yi = resp
+        .pointer("/yi")
+        .ok_or(Error::ResultError("invalid result for yi"))?
+        .as_str()
+        .ok_or(Error::ResultError("parse failed for yi"))?;
+    let ji = resp
+        .pointer("/ji")
+        .ok_or(Error::ResultError("invalid result for ji"))?
+        .as_str()
+        .ok_or(Error::ResultError("parse failed for ji"))?;
+
+    Ok(format!("宜：{}\n忌: {}", yi, ji))
+}
+
 #[tokio::test]
 async fn test_huangli() {
-    let mut huangli = HuangLi::new();
-    match huangli.on_message("算命").await {
+    use std::sync::Arc;
+    use crate::{config::ChatConfig, state::BotState};
+    let config = Config::builder().env(std::env::vars()).build().unwrap();
+    let state = Arc::new(BotState::new(config).unwrap());
+    let ctx = state.context(ChatConfig::new("room"), "user").await;
+    let huangli = HuangLi::new();
+    match huangli.on_message(&ctx, "算命").await {
         Ok(v) => println!("{}", v),
         Err(e) => println!("{:?}", e),
     }
 }
+
+#[tokio::test]
+async fn test_huangli_connection_failure() {
+    let e = get_huangli(&reqwest::Client::new(), &upstream::unreachable_config()).await.unwrap_err();
+    assert!(upstream::is_transport_error(&e), "{e:?}");
+    // 错误信息里没有 key
+    assert!(!e.to_string().contains("key="), "{e}");
+}
@@ -7,6 +7,28 @@ pub mod error;
 pub mod service;
 
 pub mod handler;
+pub mod middleware;
+pub mod dispatch;
+pub mod grpc;
+pub mod http;
+pub mod webhook;
+pub mod adapter;
+pub mod telegram;
+pub mod matrix;
+pub mod reflection;
+pub mod state;
+pub mod upstream;
+pub mod metrics;
+pub mod logging;
+pub mod replay;
+pub mod admin;
+pub mod ratelimit;
+pub mod usage;
+pub mod storage;
+pub mod plugin;
+pub mod script;
+#[cfg(test)]
+mod testing;
 
 // trigger handlers
 pub mod basic_market_info;
@@ -0,0 +1,167 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use anyhow::{Context as _, Result};
+use regex::Regex;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::config::{Config, LogFormat, LogRotation};
+
+// url 参数和 Authorization 头中常见的密钥
+const SECRET_PATTERN: &str =
+    r"(?i)\b((?:appkey|app_key|apikey|api_key|key|sign|token|access_token|secret)=)[^&\s'\x22)]+|(Bearer\s+)[^\s'\x22]+";
+
+/// 去掉日志中的密钥：url 中 appkey、sign、token 等参数的值，Bearer 令牌，以及配置中的密钥原文
+#[derive(Clone)]
+pub struct Redactor {
+    pattern: Arc<Regex>,
+    secrets: Arc<Vec<String>>,
+}
+
+impl Redactor {
+    pub fn new<S: Into<String>>(secrets: impl IntoIterator<Item = S>) -> Self {
+        // 太短的值容易误伤正常内容
+        let mut secrets: Vec<String> = secrets.into_iter().map(Into::into).filter(|s| s.len() >= 6).collect();
+        // 先替换长的，避免一个密钥是另一个的前缀时替换不干净
+        secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        Redactor {
+            pattern: Arc::new(Regex::new(SECRET_PATTERN).expect("invalid secret pattern")),
+            secrets: Arc::new(secrets),
+        }
+    }
+
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = self.pattern.replace_all(text, "$1$2***");
+        for secret in self.secrets.iter() {
+            if text.contains(secret.as_str()) {
+                text = Cow::Owned(text.replace(secret.as_str(), "***"));
+            }
+        }
+        text
+    }
+}
+
+/// 写出前先去掉密钥，fmt 每条日志只调用一次 write
+pub struct RedactWriter<W> {
+    inner: W,
+    redactor: Redactor,
+}
+
+impl<W: Write> Write for RedactWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.inner.write_all(self.redactor.redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct RedactMakeWriter<M> {
+    inner: M,
+    redactor: Redactor,
+}
+
+impl<M> RedactMakeWriter<M> {
+    pub fn new(inner: M, redactor: Redactor) -> Self {
+        RedactMakeWriter { inner, redactor }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactMakeWriter<M> {
+    type Writer = RedactWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactWriter {
+            inner: self.inner.make_writer(),
+            redactor: self.redactor.clone(),
+        }
+    }
+}
+
+/// 退出前需要 drop，保证文件日志写完
+pub struct LogGuard {
+    _guards: Vec<WorkerGuard>,
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+fn fmt_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).with_span_list(false).boxed(),
+    }
+}
+
+/// 按配置初始化日志，RUST_LOG 优先于 log.level；log 宏的输出也会经过这里
+pub fn init(config: &Config) -> Result<LogGuard> {
+    let log = &config.log;
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&log.level))
+        .with_context(|| format!("invalid log level {}", log.level))?;
+    let redactor = Redactor::new(config.secret_values());
+
+    let mut layers: Vec<BoxedLayer> = Vec::new();
+    let mut guards = Vec::new();
+    if log.console {
+        layers.push(fmt_layer(log.format, RedactMakeWriter::new(io::stdout, redactor.clone()), true));
+    }
+    if let Some(path) = log.file.as_ref().filter(|p| !p.as_os_str().is_empty()) {
+        let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(std::path::Path::new("."));
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("app.log");
+        std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        let rotation = match log.rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        };
+        let appender = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix(name)
+            .max_log_files(log.max_files.max(1))
+            .build(dir)
+            .with_context(|| format!("failed to open log file {}", path.display()))?;
+        let (writer, guard) = tracing_appender::non_blocking(appender);
+        layers.push(fmt_layer(log.format, RedactMakeWriter::new(writer, redactor), false));
+        guards.push(guard);
+    }
+    tracing_subscriber::registry().with(layers).with(filter).try_init()?;
+    Ok(LogGuard { _guards: guards })
+}
+
+#[test]
+fn test_redact() {
+    let redactor = Redactor::new(["sk-abcdef123456", "short"]);
+    let url = "https://sapi.k780.com/?app=finance.globalindex&inxids=HSI&appkey=10003&sign=b59bc3ef6191eb9f747dd4e83c99f2a4&format=json";
+    assert_eq!(
+        redactor.redact(url),
+        "https://sapi.k780.com/?app=finance.globalindex&inxids=HSI&appkey=***&sign=***&format=json"
+    );
+    assert_eq!(
+        redactor.redact("error sending request for url (https://api.tanshu.com/gold?key=abc123)"),
+        "error sending request for url (https://api.tanshu.com/gold?key=***)"
+    );
+    assert_eq!(redactor.redact("authorization: Bearer sk-xyz"), "authorization: Bearer ***");
+    assert_eq!(redactor.redact(r#"{"token":"sk-abcdef123456"}"#), r#"{"token":"***"}"#);
+    assert_eq!(redactor.redact("short and monkey=1"), "short and monkey=1");
+
+    let mut out = Vec::new();
+    let mut writer = RedactWriter {
+        inner: &mut out,
+        redactor,
+    };
+    writer.write_all(b"GET /?appkey=10003 done\n").unwrap();
+    assert_eq!(out, b"GET /?appkey=*** done\n");
+}
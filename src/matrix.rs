@@ -0,0 +1,260 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::Local;
+use serde_json::{Value, json};
+use tokio::sync::Mutex;
+
+use crate::adapter::{ChatAdapter, Incoming, Reply};
+use crate::config::MatrixConfig;
+use crate::error::Error;
+use crate::proxy::Message;
+use crate::upstream;
+
+/// Matrix client-server API 的接入：/sync 长轮询，m.room.message 回复原消息
+///
+/// 只处理已加入房间中的文本消息，房间都当作群聊，私聊房间可以在 rooms 中配置 require_mention = false
+pub struct MatrixAdapter {
+    config: MatrixConfig,
+    client: reqwest::Client,
+    since: Mutex<Option<String>>,
+    txn: AtomicU64,
+}
+
+impl MatrixAdapter {
+    /// client 一般为 BotState::http()
+    pub fn new(config: MatrixConfig, client: reqwest::Client) -> Self {
+        MatrixAdapter {
+            config,
+            client,
+            since: Mutex::new(None),
+            txn: AtomicU64::new(0),
+        }
+    }
+
+    // 例如 @bot:matrix.org 的 bot
+    fn localpart(&self) -> &str {
+        let user = self.config.user_id.trim_start_matches('@');
+        user.split(':').next().unwrap_or(user)
+    }
+
+    fn url(&self, segments: &[&str]) -> Result<reqwest::Url> {
+        let mut url = reqwest::Url::parse(&self.config.homeserver)?;
+        url.path_segments_mut()
+            .map_err(|_| Error::ConfigError(format!("invalid homeserver {}", self.config.homeserver)))?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3"])
+            .extend(segments);
+        Ok(url)
+    }
+
+    // 以 "bot: " 或者 "@bot:matrix.org " 开头时返回之后的内容
+    fn strip_mention<'a>(&self, text: &'a str) -> Option<&'a str> {
+        [self.config.user_id.as_str(), self.localpart()].into_iter().find_map(|prefix| {
+            text.strip_prefix(prefix)
+                .filter(|rest| rest.is_empty() || rest.starts_with([':', ' ']))
+                .map(|rest| rest.trim_start_matches(':').trim())
+        })
+    }
+
+    fn parse(&self, room_id: &str, event: &Value) -> Option<Incoming> {
+        if event["type"] != "m.room.message" || event["content"]["msgtype"] != "m.text" {
+            return None;
+        }
+        let sender = event["sender"].as_str()?;
+        if sender == self.config.user_id {
+            return None;
+        }
+        let body = event["content"]["body"].as_str()?;
+        // 回复别人时 body 开头是 "> " 引用的原消息，空一行之后才是正文
+        let text = body
+            .lines()
+            .skip_while(|l| l.starts_with("> ") || l.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let stripped = self.strip_mention(&text);
+        let mentioned = stripped.is_some()
+            || text.contains(&self.config.user_id)
+            || event["content"]["m.mentions"]["user_ids"]
+                .as_array()
+                .is_some_and(|ids| ids.iter().any(|id| id == self.config.user_id.as_str()));
+        let event_id = event["event_id"].as_str()?;
+        Some(Incoming {
+            message: Message {
+                is_room: true,
+                is_memtioned: mentioned,
+                room_id: room_id.to_string(),
+                content: stripped.unwrap_or(text.trim()).to_string(),
+                sender_id: sender.to_string(),
+                message_id: event_id.to_string(),
+            },
+            reply_to: event_id.to_string(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl ChatAdapter for MatrixAdapter {
+    fn name(&self) -> &str {
+        "matrix"
+    }
+
+    async fn poll(&self) -> Result<Vec<Incoming>> {
+        let mut since = self.since.lock().await;
+        let mut url = self.url(&["sync"])?;
+        // 第一次 sync 只记录位置，不处理启动前的历史消息
+        let timeout = if since.is_some() { self.config.poll_timeout * 1000 } else { 0 };
+        url.query_pairs_mut().append_pair("timeout", &timeout.to_string());
+        if let Some(since) = since.as_deref() {
+            url.query_pairs_mut().append_pair("since", since);
+        }
+        let req = self
+            .client
+            .get(url)
+            .bearer_auth(&self.config.access_token)
+            .timeout(Duration::from_secs(self.config.poll_timeout + 10));
+        let resp = upstream::json(req).await?;
+        let next = resp["next_batch"]
+            .as_str()
+            .ok_or(Error::ResultError("sync returned no next_batch"))?
+            .to_string();
+        let first = since.replace(next).is_none();
+        if first {
+            return Ok(Vec::new());
+        }
+        let mut messages = Vec::new();
+        if let Some(rooms) = resp["rooms"]["join"].as_object() {
+            for (room_id, room) in rooms {
+                let events = room["timeline"]["events"].as_array().map(Vec::as_slice).unwrap_or_default();
+                messages.extend(events.iter().filter_map(|e| self.parse(room_id, e)));
+            }
+        }
+        Ok(messages)
+    }
+
+    async fn send(&self, incoming: &Incoming, reply: &Reply) -> Result<()> {
+        // 机器人的回复通常用 m.notice，这里只有限流提示和出错用 m.notice，正常回复用 m.text
+        let (text, msgtype) = match reply {
+            Reply::None => return Ok(()),
+            Reply::Text(text) => (text, "m.text"),
+            Reply::Notice(text) | Reply::Error(text) => (text, "m.notice"),
+        };
+        let txn = format!("wb{}-{}", Local::now().timestamp_millis(), self.txn.fetch_add(1, Ordering::SeqCst));
+        let room_id = &incoming.message.room_id;
+        let url = self.url(&["rooms", room_id, "send", "m.room.message", &txn])?;
+        let body = json!({
+            "msgtype": msgtype,
+            "body": text,
+            "m.relates_to": {"m.in_reply_to": {"event_id": incoming.reply_to}},
+        });
+        let req = self
+            .client
+            .put(url)
+            .bearer_auth(&self.config.access_token)
+            .timeout(Duration::from_secs(10))
+            .json(&body);
+        upstream::json(req).await?;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_matrix() {
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    use axum::extract::{Path, Query, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::{get, put};
+    use axum::{Json, Router};
+
+    use crate::config::Config;
+    use crate::dispatch::Dispatcher;
+    use crate::handler::HandlerMgr;
+    use crate::state::BotState;
+
+    #[derive(Clone, Default)]
+    struct Mock {
+        syncs: Arc<StdMutex<u32>>,
+        sent: Arc<StdMutex<Vec<(String, Value)>>>,
+    }
+    let text = |id: &str, sender: &str, body: &str| {
+        json!({"type": "m.room.message", "event_id": id, "sender": sender, "content": {"msgtype": "m.text", "body": body}})
+    };
+    let timeline = |events: Vec<Value>| json!({"rooms": {"join": {"!room:hs": {"timeline": {"events": events}}}}});
+    let first = timeline(vec![text("$old", "@alice:hs", "bot: help")]);
+    let second = timeline(vec![
+        text("$1", "@alice:hs", "> <@bob:hs> 在吗\n\nbot: /help"),
+        text("$2", "@alice:hs", "今天天气不错"),
+        text("$3", "@bot:hs", "bot: /help"),
+        text("$4", "@alice:hs", "@bot:hs /nope"),
+        json!({"type": "m.room.member", "event_id": "$5", "sender": "@alice:hs", "content": {}}),
+    ]);
+    let sync = move |State(mock): State<Mock>, headers: HeaderMap, Query(query): Query<Vec<(String, String)>>| {
+        let (first, second) = (first.clone(), second.clone());
+        async move {
+            if headers["authorization"] != "Bearer T0KEN" {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+            let count = {
+                let mut syncs = mock.syncs.lock().unwrap();
+                *syncs += 1;
+                *syncs
+            };
+            let since = query.iter().find(|(k, _)| k == "since").map(|(_, v)| v.as_str());
+            let mut resp = match (count, since) {
+                (1, None) => first,
+                (2, Some("b1")) => second,
+                _ => {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    json!({})
+                }
+            };
+            resp["next_batch"] = json!(format!("b{count}"));
+            Ok(Json(resp))
+        }
+    };
+    async fn send(State(mock): State<Mock>, Path((room, _txn)): Path<(String, String)>, Json(body): Json<Value>) -> Json<Value> {
+        mock.sent.lock().unwrap().push((room, body));
+        Json(json!({"event_id": "$reply"}))
+    }
+    let mock = Mock::default();
+    let app = Router::new()
+        .route("/_matrix/client/v3/sync", get(sync))
+        .route("/_matrix/client/v3/rooms/{room}/send/m.room.message/{txn}", put(send))
+        .with_state(mock.clone());
+    let addr = crate::testing::serve(app).await;
+
+    let config = MatrixConfig {
+        homeserver: format!("http://{addr}"),
+        access_token: String::from("T0KEN"),
+        user_id: String::from("@bot:hs"),
+        poll_timeout: 1,
+    };
+    let adapter = MatrixAdapter::new(config, reqwest::Client::new());
+    let config = Config::builder().set("rooms", r#"[{"id": "!room:hs"}]"#).build().unwrap();
+    let mut handlers = HandlerMgr::new();
+    handlers.register_handler(crate::help::Help::new());
+    let dispatcher = Arc::new(Dispatcher::new(Arc::new(BotState::new(config).unwrap()), handlers, 4));
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    let task = tokio::spawn(crate::adapter::run(Arc::new(adapter), dispatcher, stopped));
+
+    for _ in 0..100 {
+        if mock.sent.lock().unwrap().len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    stop.send(true).unwrap();
+    task.await.unwrap();
+
+    let mut sent = mock.sent.lock().unwrap().clone();
+    sent.sort_by_key(|(_, body)| body["m.relates_to"]["m.in_reply_to"]["event_id"].as_str().unwrap().to_string());
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0].0, "!room:hs");
+    assert_eq!(sent[0].1["m.relates_to"]["m.in_reply_to"]["event_id"], "$1");
+    assert_eq!(sent[0].1["msgtype"], "m.text");
+    assert!(sent[0].1["body"].as_str().unwrap().starts_with("可用指令列表"));
+    assert_eq!(sent[1].1["m.relates_to"]["m.in_reply_to"]["event_id"], "$4");
+    assert_eq!(sent[1].1["msgtype"], "m.notice");
+}
@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::error::{ErrorKind, classify};
+use crate::gpt::Usage;
+
+// 上游接口和指令的耗时从几十毫秒到几十秒不等
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Prometheus 指标，通过 http 服务的 /metrics 导出
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    handler_latency: HistogramVec,
+    upstream_requests: IntCounterVec,
+    upstream_latency: HistogramVec,
+    llm_tokens: IntCounterVec,
+    rate_limited: IntCounterVec,
+}
+
+/// 请求结果的标签值
+pub fn outcome<T>(result: &anyhow::Result<T>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(e) => match classify(e) {
+            ErrorKind::User => "user_error",
+            ErrorKind::Upstream => "upstream_error",
+            ErrorKind::RateLimited => "rate_limited",
+            ErrorKind::Internal => "internal_error",
+        },
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some(String::from("wechat_bot")), None)
+            .expect("invalid metrics prefix");
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let c = IntCounterVec::new(Opts::new(name, help), labels).expect("invalid counter");
+            registry.register(Box::new(c.clone())).expect("duplicate counter");
+            c
+        };
+        let histogram = |name: &str, help: &str, labels: &[&str]| {
+            let opts = HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec());
+            let h = HistogramVec::new(opts, labels).expect("invalid histogram");
+            registry.register(Box::new(h.clone())).expect("duplicate histogram");
+            h
+        };
+        Metrics {
+            requests: counter("requests_total", "Handled messages", &["room", "command", "outcome"]),
+            handler_latency: histogram("handler_duration_seconds", "Handler latency", &["command"]),
+            upstream_requests: counter("upstream_requests_total", "Upstream API calls", &["provider", "outcome"]),
+            upstream_latency: histogram("upstream_duration_seconds", "Upstream API latency", &["provider"]),
+            llm_tokens: counter("llm_tokens_total", "LLM tokens", &["model", "kind"]),
+            rate_limited: counter("rate_limited_total", "Rate limit rejections", &["command"]),
+            registry,
+        }
+    }
+
+    pub fn observe_request(&self, room: &str, command: &str, outcome: &str) {
+        self.requests.with_label_values(&[room, command, outcome]).inc();
+    }
+
+    pub fn observe_handler(&self, command: &str, elapsed: Duration) {
+        self.handler_latency
+            .with_label_values(&[command])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_upstream(&self, provider: &str, ok: bool, elapsed: Duration) {
+        let outcome = if ok { "ok" } else { "error" };
+        self.upstream_requests.with_label_values(&[provider, outcome]).inc();
+        self.upstream_latency
+            .with_label_values(&[provider])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_tokens(&self, model: &str, usage: &Usage) {
+        self.llm_tokens
+            .with_label_values(&[model, "prompt"])
+            .inc_by(usage.prompt_tokens);
+        self.llm_tokens
+            .with_label_values(&[model, "completion"])
+            .inc_by(usage.completion_tokens);
+    }
+
+    pub fn observe_rate_limited(&self, command: &str) {
+        self.rate_limited.with_label_values(&[command]).inc();
+    }
+
+    /// Prometheus 文本格式
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            log::error!("failed to encode metrics, err: {:?}", e);
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+#[test]
+fn test_metrics() {
+    let metrics = Metrics::new();
+    metrics.observe_request("room", "market", outcome(&Ok(())));
+    metrics.observe_request("room", "market", outcome::<()>(&Err(crate::error::Error::RateLimited(1).into())));
+    metrics.observe_handler("market", Duration::from_millis(300));
+    metrics.observe_upstream("okx", false, Duration::from_millis(20));
+    metrics.observe_tokens("gpt-4o-mini", &Usage { prompt_tokens: 10, completion_tokens: 5, total_tokens: 15 });
+    metrics.observe_rate_limited("gpt");
+
+    let text = metrics.render();
+    assert!(text.contains(r#"wechat_bot_requests_total{command="market",outcome="ok",room="room"} 1"#));
+    assert!(text.contains(r#"outcome="rate_limited""#));
+    assert!(text.contains(r#"wechat_bot_handler_duration_seconds_bucket{command="market",le="0.5"} 1"#));
+    assert!(text.contains(r#"wechat_bot_upstream_requests_total{outcome="error",provider="okx"} 1"#));
+    assert!(text.contains(r#"wechat_bot_llm_tokens_total{kind="completion",model="gpt-4o-mini"} 5"#));
+    assert!(text.contains(r#"wechat_bot_rate_limited_total{command="gpt"} 1"#));
+}
@@ -0,0 +1,136 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::info;
+
+use crate::handler::Context;
+use crate::proxy::{Message, MessageResp, RespCode};
+use crate::state::BotState;
+
+/// 经过中间件链的一条消息
+pub struct Request {
+    pub message: Message,
+    /// 去掉首尾空白后的消息内容
+    pub content: String,
+    /// 私聊，或者群聊中 @ 了机器人（或者该群不要求 @）
+    pub addressed: bool,
+    pub received_at: Instant,
+    pub state: Arc<BotState>,
+    /// 由 RoomFilter 设置，为空时消息不会被处理
+    pub ctx: Option<Context>,
+}
+
+impl Request {
+    /// 没有带 message_id 的消息会生成一个随机 id
+    pub fn new(state: Arc<BotState>, mut message: Message) -> Self {
+        if message.message_id.is_empty() {
+            message.message_id = format!("{:016x}", rand::random::<u64>());
+        }
+        Request {
+            content: message.content.trim().to_string(),
+            message,
+            addressed: false,
+            received_at: Instant::now(),
+            state,
+            ctx: None,
+        }
+    }
+}
+
+/// 包在指令分发和 GPT 兜底外面的中间件，按注册顺序执行 before，逆序执行 after
+#[async_trait::async_trait]
+pub trait Middleware: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// 返回 Some 时直接以此回复，跳过后面的中间件和分发
+    async fn before(&self, _req: &mut Request) -> Option<MessageResp> {
+        None
+    }
+
+    /// 只有 before 执行过的中间件才会执行 after，包括短路的那个
+    async fn after(&self, _req: &Request, _resp: &mut MessageResp) {}
+}
+
+pub fn ignore() -> MessageResp {
+    MessageResp {
+        code: RespCode::Ignore.into(),
+        response: String::new(),
+    }
+}
+
+/// 按配置过滤群聊/私聊和 @，并为消息生成 Context；禁言期间只放行管理员的 /admin
+#[derive(Default)]
+pub struct RoomFilter {}
+
+impl RoomFilter {
+    pub fn new() -> Self {
+        RoomFilter {}
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for RoomFilter {
+    fn name(&self) -> &'static str {
+        "room_filter"
+    }
+
+    async fn before(&self, req: &mut Request) -> Option<MessageResp> {
+        let config = req.state.config().await;
+        let msg = &req.message;
+        // 没有 @ 机器人的群消息只在开启 trigger_without_mention 时响应自由文本触发
+        let (chat, addressed) = if msg.is_room {
+            match config.room(&msg.room_id) {
+                Some(room) => (Some(room), msg.is_memtioned || !room.require_mention),
+                None => (None, false),
+            }
+        } else {
+            (config.user(&msg.sender_id), true)
+        };
+        let Some(chat) = chat.filter(|chat| addressed || chat.trigger_without_mention) else {
+            return Some(ignore());
+        };
+        let ctx = req.state.context(chat.clone(), &msg.sender_id).await;
+        if ctx.is_muted() && !(ctx.is_admin() && req.content.starts_with("/admin")) {
+            return Some(ignore());
+        }
+        req.addressed = addressed;
+        req.ctx = Some(ctx);
+        None
+    }
+}
+
+/// 记录每条消息的来源、结果和耗时
+#[derive(Default)]
+pub struct RequestLog {}
+
+impl RequestLog {
+    pub fn new() -> Self {
+        RequestLog {}
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for RequestLog {
+    fn name(&self) -> &'static str {
+        "request_log"
+    }
+
+    async fn before(&self, req: &mut Request) -> Option<MessageResp> {
+        let msg = &req.message;
+        info!(
+            "recv room: {}, sender: {}, mentioned: {}, msg: {}",
+            msg.room_id, msg.sender_id, msg.is_memtioned, req.content
+        );
+        None
+    }
+
+    async fn after(&self, req: &Request, resp: &mut MessageResp) {
+        info!(
+            "resp room: {}, sender: {}, code: {:?}, elapsed: {}ms",
+            req.message.room_id,
+            req.message.sender_id,
+            resp.code(),
+            req.received_at.elapsed().as_millis()
+        );
+    }
+}
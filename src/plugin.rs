@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+
+use crate::config::PluginConfig;
+use crate::error::Error;
+use crate::handler::{Context, Handler, HandlerMgr, Trigger};
+
+/// 插件目录下的 plugin.toml
+#[derive(Debug, Deserialize, Clone)]
+pub struct PluginManifest {
+    pub name: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub description: String,
+    /// 启动命令及参数，在插件目录下执行
+    pub command: Vec<String>,
+    /// 处理的 / 指令，消息等于指令或者以指令加空格开头时匹配
+    #[serde(default)]
+    pub commands: Vec<String>,
+    /// 自由文本触发的关键词
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// 自由文本触发的正则
+    #[serde(default)]
+    pub regex: Vec<String>,
+    #[serde(default)]
+    pub priority: i32,
+    /// 传给插件进程的环境变量，除 PATH 外不会继承机器人的环境变量（其中的密钥）
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// 单次调用时限（秒），不能超过 plugins.timeout
+    pub timeout: Option<u64>,
+}
+
+/// 外部进程插件，每条消息启动一次进程，通过 stdin/stdout 交换一行 JSON-RPC 2.0 消息：
+///
+/// 请求 `{"jsonrpc":"2.0","id":1,"method":"on_message","params":{"message":"...","chat_id":"...","sender_id":"...","is_admin":false}}`
+///
+/// 回复 `{"jsonrpc":"2.0","id":1,"result":{"reply":"..."}}`，或者 `{"jsonrpc":"2.0","id":1,"error":{"code":-32602,"message":"..."}}`，
+/// 其中 -32602 表示用户输入有误，会原样回复给用户
+///
+/// 插件进程的限制：
+/// - 工作目录为插件目录，插件目录不能通过符号链接指向 plugins.dir 之外
+/// - 除 PATH 和 manifest 中的 env 外不继承环境变量
+/// - 运行时间、输出大小，以及 CPU 时间、虚拟内存、进程数（rlimit）
+/// - 在单独的进程组中运行，调用结束或超时后整个进程组被结束，插件启动的子进程不会残留
+///
+/// 插件仍以机器人的用户身份运行，能读写该用户可以访问的文件、访问网络，只应加载可信的插件
+pub struct PluginHandler {
+    manifest: PluginManifest,
+    dir: PathBuf,
+    timeout: Duration,
+    max_output: usize,
+    limits: Limits,
+}
+
+/// 插件进程的 rlimit，0 表示不限制
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    cpu_seconds: u64,
+    max_memory: u64,
+    max_processes: u64,
+}
+
+impl Limits {
+    // 在 fork 之后、exec 之前执行，只能调用 async-signal-safe 的函数
+    #[cfg(unix)]
+    fn apply(&self) -> std::io::Result<()> {
+        let limits = [
+            (libc::RLIMIT_CPU, self.cpu_seconds),
+            (libc::RLIMIT_AS, self.max_memory),
+            (libc::RLIMIT_NPROC, self.max_processes),
+        ];
+        for (resource, value) in limits {
+            if value == 0 {
+                continue;
+            }
+            let limit = libc::rlimit {
+                rlim_cur: value as libc::rlim_t,
+                rlim_max: value as libc::rlim_t,
+            };
+            if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// drop 时结束整个进程组，需要在 Child 之后创建，保证组长还没有被回收、进程组 id 不会被复用
+struct ProcessGroup(Option<u32>);
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.0 {
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+            }
+        }
+    }
+}
+
+impl PluginHandler {
+    /// 读取插件目录下的 plugin.toml
+    pub fn load(dir: &Path, config: &PluginConfig) -> Result<Self> {
+        let content = fs::read_to_string(dir.join("plugin.toml"))?;
+        let manifest: PluginManifest =
+            toml::from_str(&content).map_err(|e| Error::ConfigError(format!("{}: {}", dir.display(), e)))?;
+        if manifest.name.is_empty() || manifest.command.is_empty() {
+            Err(Error::ConfigError(format!("{}: name and command are required", dir.display())))?
+        }
+        // 提前检查正则，避免注册时才失败
+        for regex in &manifest.regex {
+            Trigger::regex(regex, manifest.priority)?;
+        }
+        let timeout = manifest.timeout.unwrap_or(config.timeout).min(config.timeout);
+        Ok(PluginHandler {
+            manifest,
+            dir: dir.to_path_buf(),
+            timeout: Duration::from_secs(timeout),
+            max_output: config.max_output,
+            limits: Limits {
+                cpu_seconds: config.cpu_seconds,
+                max_memory: config.max_memory,
+                max_processes: config.max_processes,
+            },
+        })
+    }
+
+    pub fn manifest(&self) -> &PluginManifest {
+        &self.manifest
+    }
+
+    fn plugin_error(&self, message: impl Into<String>) -> Error {
+        Error::PluginError {
+            plugin: self.manifest.name.clone(),
+            message: message.into(),
+        }
+    }
+
+    fn command(&self) -> Command {
+        let command = &self.manifest.command;
+        let mut cmd = Command::new(&command[0]);
+        cmd.args(&command[1..])
+            .current_dir(&self.dir)
+            .env_clear()
+            .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+            .envs(&self.manifest.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+        #[cfg(unix)]
+        {
+            let limits = self.limits;
+            cmd.process_group(0);
+            // SAFETY: apply 只调用 setrlimit，可以在 fork 之后的子进程中执行
+            unsafe {
+                cmd.pre_exec(move || limits.apply());
+            }
+        }
+        cmd
+    }
+
+    async fn call(&self, params: Value) -> Result<Value> {
+        let mut child = self
+            .command()
+            .spawn()
+            .map_err(|e| self.plugin_error(format!("failed to start: {e}")))?;
+        let _group = ProcessGroup(child.id());
+
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "on_message", "params": params});
+        let mut stdin = child.stdin.take().ok_or_else(|| self.plugin_error("no stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| self.plugin_error("no stdout"))?;
+        let mut line = String::new();
+        let exchange = async {
+            stdin.write_all(format!("{request}\n").as_bytes()).await?;
+            drop(stdin);
+            BufReader::new(stdout.take(self.max_output as u64)).read_line(&mut line).await
+        };
+        match tokio::time::timeout(self.timeout, exchange).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => Err(self.plugin_error(format!("io error: {e}")))?,
+            Err(_) => Err(Error::Timeout(self.timeout.as_secs()))?,
+        }
+        // 只读一行回复，之后不管进程是否退出都结束它，返回时 _group 再结束它启动的子进程
+        child.start_kill().ok();
+
+        if !line.ends_with('\n') && line.len() >= self.max_output {
+            Err(self.plugin_error(format!("output exceeds {} bytes", self.max_output)))?
+        }
+        let resp: Value = serde_json::from_str(line.trim())
+            .map_err(|e| self.plugin_error(format!("invalid response: {e}")))?;
+        if let Some(err) = resp.get("error") {
+            let message = err["message"].as_str().unwrap_or("unknown error").to_string();
+            if err["code"].as_i64() == Some(-32602) {
+                return Err(Error::ParamError(message).into());
+            }
+            return Err(self.plugin_error(message).into());
+        }
+        resp.get("result").cloned().ok_or_else(|| self.plugin_error("missing result").into())
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler for PluginHandler {
+    fn name(&self) -> &str {
+        &self.manifest.name
+    }
+
+    fn matches(&self, msg: &str) -> bool {
+        self.manifest.commands.iter().any(|c| {
+            msg.strip_prefix(c.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+        })
+    }
+
+    fn triggers(&self) -> Vec<Trigger> {
+        let priority = self.manifest.priority;
+        let mut triggers = Vec::new();
+        if !self.manifest.keywords.is_empty() {
+            let keywords: Vec<&str> = self.manifest.keywords.iter().map(|k| k.as_str()).collect();
+            triggers.push(Trigger::keywords(&keywords, priority));
+        }
+        // 正则在 load 时已经检查过
+        triggers.extend(self.manifest.regex.iter().filter_map(|r| Trigger::regex(r, priority).ok()));
+        triggers
+    }
+
+    async fn on_message(&self, ctx: &Context, msg: &str) -> Result<String> {
+        info!("plugin {} msg: {}", self.manifest.name, msg);
+        let params = json!({
+            "message": msg,
+            "chat_id": ctx.chat.id,
+            "sender_id": ctx.sender_id,
+            "is_admin": ctx.is_admin(),
+        });
+        let result = self.call(params).await?;
+        Ok(result["reply"].as_str().unwrap_or_default().to_string())
+    }
+}
+
+/// 加载插件目录下的全部插件，插件目录读取失败或者单个插件加载失败只打日志，
+/// 和已有指令重名、或者实际位置不在插件目录下的插件会被跳过
+pub fn register_plugins(mgr: &mut HandlerMgr, config: &PluginConfig) -> Result<usize> {
+    let Some(dir) = &config.dir else {
+        return Ok(0);
+    };
+    let read = fs::canonicalize(dir).and_then(|root| fs::read_dir(&root).map(|entries| (root, entries)));
+    let (root, entries) = match read {
+        Ok(read) => read,
+        Err(e) => {
+            warn!("failed to read plugins dir {}, no plugin loaded, err: {}", dir.display(), e);
+            return Ok(0);
+        }
+    };
+    let mut dirs: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.join("plugin.toml").is_file())
+        .filter_map(|p| match fs::canonicalize(&p) {
+            Ok(real) if real.parent() == Some(root.as_path()) => Some(real),
+            _ => {
+                warn!("plugin {} is outside of {}, skipped", p.display(), root.display());
+                None
+            }
+        })
+        .collect();
+    dirs.sort();
+
+    let mut count = 0;
+    for dir in dirs {
+        let plugin = match PluginHandler::load(&dir, config) {
+            Ok(plugin) => plugin,
+            Err(e) => {
+                error!("failed to load plugin {}, err: {:?}", dir.display(), e);
+                continue;
+            }
+        };
+        let name = plugin.name().to_string();
+        if config.enabled.as_ref().is_some_and(|enabled| !enabled.contains(&name)) {
+            continue;
+        }
+        if mgr.handler_names().contains(&name.as_str()) {
+            warn!("plugin {} conflicts with an existing handler, skipped", name);
+            continue;
+        }
+        info!("load plugin {} {} from {}", name, plugin.manifest.version, dir.display());
+        mgr.register_handler(plugin);
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[tokio::test]
+async fn test_plugins() {
+    use std::sync::Arc;
+
+    use crate::config::{ChatConfig, Config};
+    use crate::help::Help;
+    use crate::state::BotState;
+
+    let root = crate::testing::TempDir::new("plugins");
+    let plugin = |name: &str, manifest: &str, script: &str| {
+        let dir = root.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("plugin.toml"), manifest).unwrap();
+        fs::write(dir.join("main.sh"), script).unwrap();
+    };
+    plugin(
+        "echo",
+        r#"
+name = "echo"
+command = ["sh", "main.sh"]
+commands = ["echo"]
+keywords = ["复读"]
+env = { GREETING = "hi" }
+"#,
+        // 只回显 message 字段以外的环境变量，验证没有继承 HOME
+        "read line\necho \"{\\\"jsonrpc\\\":\\\"2.0\\\",\\\"id\\\":1,\\\"result\\\":{\\\"reply\\\":\\\"$GREETING${HOME:-}\\\"}}\"\n",
+    );
+    plugin(
+        "bad",
+        "name = \"bad\"\ncommand = [\"sh\", \"main.sh\"]\ncommands = [\"bad\"]\n",
+        "read line\necho '{\"jsonrpc\":\"2.0\",\"id\":1,\"error\":{\"code\":-32602,\"message\":\"需要参数\"}}'\n",
+    );
+    plugin(
+        "slow",
+        "name = \"slow\"\ncommand = [\"sh\", \"main.sh\"]\ncommands = [\"slow\"]\ntimeout = 1\n",
+        "sleep 5\n",
+    );
+    plugin("help", "name = \"help\"\ncommand = [\"sh\", \"main.sh\"]\n", "");
+    plugin("broken", "name = ", "");
+
+    let mut mgr = HandlerMgr::new();
+    mgr.register_handler(Help::new());
+    let config = PluginConfig {
+        dir: Some(root.path().to_path_buf()),
+        ..Default::default()
+    };
+    let missing = PluginConfig {
+        dir: Some(root.join("missing")),
+        ..Default::default()
+    };
+    assert_eq!(register_plugins(&mut mgr, &missing).unwrap(), 0);
+    // broken 加载失败，help 与内置指令重名
+    assert_eq!(register_plugins(&mut mgr, &config).unwrap(), 3);
+    assert_eq!(mgr.handler_names(), vec!["help", "bad", "echo", "slow"]);
+
+    let state = Arc::new(BotState::new(Config::builder().set("room_id", "room").build().unwrap()).unwrap());
+    let mut chat = ChatConfig::new("room");
+    chat.triggers = Some(vec![String::from("echo")]);
+    let ctx = state.context(chat, "user").await;
+    assert_eq!(mgr.match_handler(&ctx, "echo 1").await.unwrap().unwrap(), "hi");
+    assert_eq!(mgr.match_trigger(&ctx, "复读机").await.unwrap().unwrap(), "hi");
+    assert!(mgr.match_handler(&ctx, "echoes").await.unwrap().is_none());
+
+    let err = mgr.match_handler(&ctx, "bad").await.unwrap_err();
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::ParamError(m)) if m == "需要参数"));
+    let err = mgr.match_handler(&ctx, "slow").await.unwrap_err();
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Timeout(1))));
+
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_plugin_limits() {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use crate::config::{ChatConfig, Config};
+    use crate::state::BotState;
+
+    let root = crate::testing::TempDir::new("plugin-limits");
+    let outside = crate::testing::TempDir::new("plugin-outside");
+    let plugin = |dir: PathBuf, name: &str, script: &str| {
+        fs::create_dir_all(&dir).unwrap();
+        let manifest = format!("name = \"{name}\"\ncommand = [\"sh\", \"main.sh\"]\ncommands = [\"{name}\"]\n");
+        fs::write(dir.join("plugin.toml"), manifest).unwrap();
+        fs::write(dir.join("main.sh"), script).unwrap();
+    };
+    let reply = |reply: &str| format!("echo \"{{\\\"jsonrpc\\\":\\\"2.0\\\",\\\"id\\\":1,\\\"result\\\":{{\\\"reply\\\":\\\"{reply}\\\"}}}}\"\n");
+    plugin(root.join("limits"), "limits", &format!("read line\n{}", reply("$(ulimit -t) $(ulimit -v)")));
+    plugin(root.join("fork"), "fork", &format!("sleep 30 &\necho $! > child.pid\nread line\n{}", reply("ok")));
+    plugin(root.join("spin"), "spin", "read line\nwhile :; do :; done\n");
+    // 通过符号链接指向插件目录之外的插件不加载
+    plugin(outside.join("escape"), "escape", &format!("read line\n{}", reply("escaped")));
+    std::os::unix::fs::symlink(outside.join("escape"), root.join("escape")).unwrap();
+
+    let mut mgr = HandlerMgr::new();
+    let config = PluginConfig {
+        dir: Some(root.path().to_path_buf()),
+        cpu_seconds: 1,
+        max_memory: 512 * 1024 * 1024,
+        ..Default::default()
+    };
+    assert_eq!(register_plugins(&mut mgr, &config).unwrap(), 3);
+    assert_eq!(mgr.handler_names(), vec!["fork", "limits", "spin"]);
+
+    let state = Arc::new(BotState::new(Config::builder().set("room_id", "room").build().unwrap()).unwrap());
+    let ctx = state.context(ChatConfig::new("room"), "user").await;
+    assert_eq!(mgr.match_handler(&ctx, "limits").await.unwrap().unwrap(), "1 524288");
+
+    // 插件回复后，它在后台启动的子进程也被结束
+    assert_eq!(mgr.match_handler(&ctx, "fork").await.unwrap().unwrap(), "ok");
+    let pid = fs::read_to_string(root.join("fork/child.pid")).unwrap();
+    let alive = || {
+        fs::read_to_string(format!("/proc/{}/stat", pid.trim()))
+            .is_ok_and(|stat| stat.rsplit_once(") ").is_some_and(|(_, rest)| !rest.starts_with('Z')))
+    };
+    let start = Instant::now();
+    while alive() && start.elapsed() < Duration::from_secs(2) {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(!alive(), "child {} of the plugin is still running", pid.trim());
+
+    // 超出 CPU 时间的插件被系统结束，不用等到 timeout
+    let start = Instant::now();
+    let err = mgr.match_handler(&ctx, "spin").await.unwrap_err();
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::PluginError { .. })), "{err:?}");
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+}
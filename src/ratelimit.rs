@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+use crate::config::{BucketConfig, RateLimitConfig};
+
+// rejections 最多保留的条目数，超出时去掉次数最少的
+const MAX_REJECTIONS: usize = 1000;
+
+/// 令牌桶，按 per_minute 的速度回填，最多 capacity 个
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &BucketConfig, now: Instant) -> Self {
+        Bucket {
+            tokens: limit.capacity as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &BucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_minute / 60.0).min(limit.capacity as f64);
+        self.updated_at = now;
+    }
+
+    // 距离下一个令牌可用还要多久
+    fn retry_after(&self, limit: &BucketConfig) -> Duration {
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) * 60.0 / limit.per_minute)
+    }
+
+    // 已经回填满的桶和新建的一样，可以删掉
+    fn is_full(&self, limit: &BucketConfig, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens + elapsed * limit.per_minute / 60.0 >= limit.capacity as f64
+    }
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    rejections: Mutex<HashMap<String, u64>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 同时检查用户、会话、指令三个桶，全部有余量时各扣一个令牌，
+    /// 否则不扣并返回最长的等待时间
+    pub async fn check(
+        &self,
+        config: &RateLimitConfig,
+        chat_id: &str,
+        sender_id: &str,
+        command: &str,
+    ) -> Result<(), Duration> {
+        if !config.enabled {
+            return Ok(());
+        }
+        let mut keys = Vec::new();
+        if let Some(limit) = config.user {
+            keys.push((format!("user:{}", sender_id), limit));
+        }
+        if let Some(limit) = config.room {
+            keys.push((format!("room:{}", chat_id), limit));
+        }
+        if let Some(limit) = config.commands.get(command) {
+            keys.push((format!("command:{}", command), *limit));
+        }
+        // Config::validate 拒绝了 per_minute <= 0，直接构造的配置中这样的桶不生效
+        keys.retain(|(_, limit)| limit.per_minute > 0.0);
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().await;
+        RateLimiter::evict(&mut buckets, config, now);
+        let mut rejected: Option<(String, Duration)> = None;
+        for (key, limit) in &keys {
+            let bucket = buckets
+                .entry(key.clone())
+                .or_insert_with(|| Bucket::new(limit, now));
+            bucket.refill(limit, now);
+            if bucket.tokens < 1.0 {
+                let wait = bucket.retry_after(limit);
+                if rejected.as_ref().is_none_or(|(_, w)| wait > *w) {
+                    rejected = Some((key.clone(), wait));
+                }
+            }
+        }
+        if let Some((key, wait)) = rejected {
+            let mut rejections = self.rejections.lock().await;
+            if rejections.len() >= MAX_REJECTIONS && !rejections.contains_key(&key) {
+                let least = rejections.iter().min_by_key(|(_, count)| **count).map(|(k, _)| k.clone());
+                rejections.remove(&least.unwrap_or_default());
+            }
+            *rejections.entry(key).or_default() += 1;
+            return Err(wait);
+        }
+        for (key, _) in &keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    // 删掉已经回填满的桶，避免每个发送者都留下一个；找不到对应配置的桶也删掉
+    fn evict(buckets: &mut HashMap<String, Bucket>, config: &RateLimitConfig, now: Instant) {
+        buckets.retain(|key, bucket| {
+            let limit = match key.split_once(':') {
+                Some(("user", _)) => config.user,
+                Some(("room", _)) => config.room,
+                Some(("command", command)) => config.commands.get(command).copied(),
+                _ => None,
+            };
+            limit.is_some_and(|limit| limit.per_minute > 0.0 && !bucket.is_full(&limit, now))
+        });
+    }
+
+    /// 被拒绝次数，按次数从多到少排列
+    pub async fn rejections(&self) -> Vec<(String, u64)> {
+        let mut rejections: Vec<_> = self
+            .rejections
+            .lock()
+            .await
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        rejections.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        rejections
+    }
+
+    /// 当前剩余令牌，用于管理员查看
+    pub async fn tokens(&self) -> Vec<(String, f64)> {
+        let mut tokens: Vec<_> = self
+            .buckets
+            .lock()
+            .await
+            .iter()
+            .map(|(k, b)| (k.clone(), b.tokens))
+            .collect();
+        tokens.sort_by(|a, b| a.0.cmp(&b.0));
+        tokens
+    }
+
+    pub async fn reset(&self) {
+        self.buckets.lock().await.clear();
+        self.rejections.lock().await.clear();
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limiter() {
+    let config = RateLimitConfig {
+        enabled: true,
+        user: Some(BucketConfig { capacity: 2, per_minute: 1.0 }),
+        room: Some(BucketConfig { capacity: 4, per_minute: 1.0 }),
+        commands: HashMap::from([(String::from("market"), BucketConfig { capacity: 1, per_minute: 1.0 })]),
+        ..Default::default()
+    };
+    let limiter = RateLimiter::new();
+
+    assert!(limiter.check(&config, "room", "a", "help").await.is_ok());
+    assert!(limiter.check(&config, "room", "a", "help").await.is_ok());
+    // 用户 a 的桶用完了
+    let wait = limiter.check(&config, "room", "a", "help").await.unwrap_err();
+    assert!(wait > Duration::from_secs(50));
+
+    assert!(limiter.check(&config, "room", "b", "market").await.is_ok());
+    // 指令桶用完，用户和群的令牌不应被扣
+    assert!(limiter.check(&config, "room", "c", "market").await.is_err());
+    assert!(limiter.check(&config, "room", "c", "help").await.is_ok());
+    // 群的桶用完了
+    assert!(limiter.check(&config, "room", "d", "help").await.is_err());
+
+    let rejections = limiter.rejections().await;
+    assert_eq!(rejections.iter().map(|r| r.1).sum::<u64>(), 3);
+
+    // 回填满的桶在下一次 check 时被删掉
+    let fast = RateLimitConfig {
+        enabled: true,
+        user: Some(BucketConfig { capacity: 1, per_minute: 6000.0 }),
+        room: None,
+        ..Default::default()
+    };
+    let idle = RateLimiter::new();
+    for sender in ["a", "b", "c"] {
+        idle.check(&fast, "room", sender, "help").await.unwrap();
+    }
+    assert_eq!(idle.tokens().await.len(), 3);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    idle.check(&fast, "room", "d", "help").await.unwrap();
+    assert_eq!(idle.tokens().await, vec![(String::from("user:d"), 0.0)]);
+    let broken = RateLimitConfig {
+        user: Some(BucketConfig { capacity: 0, per_minute: 0.0 }),
+        ..fast
+    };
+    assert!(idle.check(&broken, "room", "e", "help").await.is_ok());
+
+    let disabled = RateLimitConfig {
+        enabled: false,
+        ..Default::default()
+    };
+    assert!(limiter.check(&disabled, "room", "a", "help").await.is_ok());
+}
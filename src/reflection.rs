@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+use prost::Message;
+use prost_types::{DescriptorProto, FileDescriptorProto, FileDescriptorSet};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+use pb::server_reflection_request::MessageRequest;
+use pb::server_reflection_response::MessageResponse;
+use pb::server_reflection_server::ServerReflection;
+use pb::{
+    ErrorResponse, FileDescriptorResponse, ListServiceResponse, ServerReflectionRequest, ServerReflectionResponse,
+    ServiceResponse,
+};
+
+pub mod pb {
+    tonic::include_proto!("grpc.reflection.v1alpha");
+}
+
+pub use pb::server_reflection_server::ServerReflectionServer;
+
+/// proxy.proto 和 reflection.proto 的描述，由 build.rs 生成
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("proxy_descriptor");
+
+#[derive(Default)]
+struct Index {
+    // 文件名 -> 文件描述
+    files: HashMap<String, FileDescriptorProto>,
+    // 符号全名 -> 文件名
+    symbols: HashMap<String, String>,
+    services: Vec<String>,
+}
+
+impl Index {
+    fn add_file(&mut self, file: FileDescriptorProto) {
+        let prefix = match file.package() {
+            "" => String::new(),
+            package => format!("{package}."),
+        };
+        let name = file.name().to_string();
+        for service in &file.service {
+            let full = format!("{prefix}{}", service.name());
+            for method in &service.method {
+                self.symbols.insert(format!("{full}.{}", method.name()), name.clone());
+            }
+            self.symbols.insert(full.clone(), name.clone());
+            self.services.push(full);
+        }
+        for message in &file.message_type {
+            self.add_message(&prefix, message, &name);
+        }
+        for e in &file.enum_type {
+            self.symbols.insert(format!("{prefix}{}", e.name()), name.clone());
+        }
+        self.files.insert(name, file);
+    }
+
+    fn add_message(&mut self, prefix: &str, message: &DescriptorProto, file: &str) {
+        let full = format!("{prefix}{}", message.name());
+        for nested in &message.nested_type {
+            self.add_message(&format!("{full}."), nested, file);
+        }
+        for e in &message.enum_type {
+            self.symbols.insert(format!("{full}.{}", e.name()), file.to_string());
+        }
+        self.symbols.insert(full, file.to_string());
+    }
+
+    // 返回文件及其全部依赖，依赖在前
+    fn file_with_deps(&self, name: &str, out: &mut Vec<Vec<u8>>, seen: &mut Vec<String>) {
+        if seen.iter().any(|s| s == name) {
+            return;
+        }
+        seen.push(name.to_string());
+        let Some(file) = self.files.get(name) else {
+            return;
+        };
+        for dep in &file.dependency {
+            self.file_with_deps(dep, out, seen);
+        }
+        out.push(file.encode_to_vec());
+    }
+
+    fn file_response(&self, name: &str) -> MessageResponse {
+        if !self.files.contains_key(name) {
+            return not_found(format!("file {name} not found"));
+        }
+        let mut files = Vec::new();
+        self.file_with_deps(name, &mut files, &mut Vec::new());
+        MessageResponse::FileDescriptorResponse(FileDescriptorResponse {
+            file_descriptor_proto: files,
+        })
+    }
+
+    fn respond(&self, req: &ServerReflectionRequest) -> MessageResponse {
+        match &req.message_request {
+            Some(MessageRequest::ListServices(_)) => MessageResponse::ListServicesResponse(ListServiceResponse {
+                service: self
+                    .services
+                    .iter()
+                    .map(|name| ServiceResponse { name: name.clone() })
+                    .collect(),
+            }),
+            Some(MessageRequest::FileByFilename(name)) => self.file_response(name),
+            Some(MessageRequest::FileContainingSymbol(symbol)) => match self.symbols.get(symbol) {
+                Some(file) => self.file_response(file),
+                None => not_found(format!("symbol {symbol} not found")),
+            },
+            // 没有使用 proto2 扩展
+            Some(MessageRequest::FileContainingExtension(_)) | Some(MessageRequest::AllExtensionNumbersOfType(_)) => {
+                not_found(String::from("extensions are not supported"))
+            }
+            None => MessageResponse::ErrorResponse(ErrorResponse {
+                error_code: tonic::Code::InvalidArgument as i32,
+                error_message: String::from("empty request"),
+            }),
+        }
+    }
+}
+
+fn not_found(message: String) -> MessageResponse {
+    MessageResponse::ErrorResponse(ErrorResponse {
+        error_code: tonic::Code::NotFound as i32,
+        error_message: message,
+    })
+}
+
+/// grpc.reflection.v1alpha 服务，供 grpcurl 等工具查看接口定义
+#[derive(Clone)]
+pub struct ReflectionService {
+    index: Arc<Index>,
+}
+
+impl ReflectionService {
+    /// 传入编码后的 FileDescriptorSet，例如 FILE_DESCRIPTOR_SET 和 tonic_health::pb::FILE_DESCRIPTOR_SET
+    pub fn new(descriptor_sets: &[&[u8]]) -> Result<Self> {
+        let mut index = Index::default();
+        for set in descriptor_sets {
+            for file in FileDescriptorSet::decode(*set)?.file {
+                index.add_file(file);
+            }
+        }
+        index.services.sort();
+        Ok(ReflectionService { index: Arc::new(index) })
+    }
+
+    pub fn services(&self) -> &[String] {
+        &self.index.services
+    }
+}
+
+#[tonic::async_trait]
+impl ServerReflection for ReflectionService {
+    type ServerReflectionInfoStream = ReceiverStream<Result<ServerReflectionResponse, Status>>;
+
+    async fn server_reflection_info(
+        &self,
+        req: Request<Streaming<ServerReflectionRequest>>,
+    ) -> Result<Response<Self::ServerReflectionInfoStream>, Status> {
+        let mut requests = req.into_inner();
+        let index = self.index.clone();
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            while let Some(req) = requests.next().await {
+                let resp = req.map(|req| ServerReflectionResponse {
+                    valid_host: req.host.clone(),
+                    message_response: Some(index.respond(&req)),
+                    original_request: Some(req),
+                });
+                if tx.send(resp).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+#[test]
+fn test_reflection() {
+    let service = ReflectionService::new(&[FILE_DESCRIPTOR_SET, tonic_health::pb::FILE_DESCRIPTOR_SET]).unwrap();
+    assert_eq!(
+        service.services(),
+        ["grpc.health.v1.Health", "grpc.reflection.v1alpha.ServerReflection", "proxy.Proxy"]
+    );
+
+    let request = |req: MessageRequest| ServerReflectionRequest {
+        host: String::new(),
+        message_request: Some(req),
+    };
+    let file = |resp: MessageResponse| match resp {
+        MessageResponse::FileDescriptorResponse(f) => {
+            FileDescriptorProto::decode(f.file_descriptor_proto[0].as_slice()).unwrap()
+        }
+        r => panic!("unexpected response {r:?}"),
+    };
+    let index = &service.index;
+    for symbol in ["proxy.Proxy", "proxy.Proxy.OnMessage", "proxy.MessageResp", "proxy.RespCode"] {
+        let resp = index.respond(&request(MessageRequest::FileContainingSymbol(symbol.to_string())));
+        assert_eq!(file(resp).name(), "proxy.proto");
+    }
+    let resp = index.respond(&request(MessageRequest::FileByFilename(String::from("health.proto"))));
+    assert_eq!(file(resp).package(), "grpc.health.v1");
+    let resp = index.respond(&request(MessageRequest::FileContainingSymbol(String::from("proxy.Nope"))));
+    assert!(matches!(resp, MessageResponse::ErrorResponse(e) if e.error_code == tonic::Code::NotFound as i32));
+}
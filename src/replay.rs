@@ -0,0 +1,498 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context as _, Result};
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, StatusCode, Uri, header};
+use axum::response::{IntoResponse, Response};
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::logging::Redactor;
+use crate::middleware::{Middleware, Request};
+use crate::proxy::proxy_server::Proxy;
+use crate::proxy::{Message, MessageResp};
+use crate::upstream;
+
+// 时间相关的参数每次请求都不同，密钥不能写进文件，录制和匹配时都去掉
+const IGNORED_PARAMS: [&str; 6] = ["after", "endTime", "date", "key", "appkey", "sign"];
+
+// golden 中匹配任意文本的占位符
+const WILDCARD: &str = "{..}";
+
+/// 读取 JSONL 文件，跳过空行
+pub fn read_jsonl<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<Vec<T>> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| serde_json::from_str(line).with_context(|| format!("{}:{}", path.display(), i + 1)))
+        .collect()
+}
+
+/// 覆盖写入 JSONL 文件
+pub fn write_jsonl<T: Serialize>(path: impl AsRef<Path>, items: &[T]) -> Result<()> {
+    let mut content = String::new();
+    for item in items {
+        content += &serde_json::to_string(item)?;
+        content.push('\n');
+    }
+    fs::write(path.as_ref(), content).with_context(|| format!("failed to write {}", path.as_ref().display()))
+}
+
+// 追加写入，每条一行
+struct JsonlWriter {
+    file: Mutex<File>,
+}
+
+impl JsonlWriter {
+    fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        Ok(JsonlWriter { file: Mutex::new(file) })
+    }
+
+    fn append<T: Serialize>(&self, item: &T) {
+        let result = serde_json::to_string(item)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(self.file.lock().unwrap(), "{line}")?));
+        if let Err(e) = result {
+            error!("failed to append record, err: {:?}", e);
+        }
+    }
+}
+
+/// 一条消息和它的回复，录制的流量和 golden 都是这个格式
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrafficEntry {
+    pub message: Message,
+    /// RespCode 的名字，例如 Ok、Ignore
+    pub code: String,
+    /// golden 中可以用 {..} 匹配任意文本
+    #[serde(default)]
+    pub response: String,
+}
+
+impl TrafficEntry {
+    pub fn new(message: Message, resp: &MessageResp) -> Self {
+        TrafficEntry {
+            message,
+            code: resp.code().as_str_name().to_string(),
+            response: resp.response.clone(),
+        }
+    }
+}
+
+/// 把匿名化后的消息和回复追加到 record.traffic
+///
+/// 配置中出现的群、用户和管理员 id 保持不变，这样用同一份配置回放时结果一致；
+/// 其它发送者 id 换成加盐的哈希，盐每次启动随机生成；消息中的密钥会被去掉
+pub struct TrafficRecorder {
+    writer: JsonlWriter,
+    redactor: Redactor,
+    salt: [u8; 16],
+}
+
+impl TrafficRecorder {
+    pub fn open(path: impl AsRef<Path>, config: &Config) -> Result<Self> {
+        Ok(TrafficRecorder {
+            writer: JsonlWriter::open(path.as_ref())?,
+            redactor: Redactor::new(config.secret_values()),
+            salt: rand::random(),
+        })
+    }
+
+    fn anonymize_id(&self, config: &Config, id: &str) -> String {
+        let known = id.is_empty()
+            || config.room(id).is_some()
+            || config.user(id).is_some()
+            || config.admins.iter().any(|a| a == id);
+        if known {
+            return id.to_string();
+        }
+        let hash = Sha256::new().chain_update(self.salt).chain_update(id).finalize();
+        format!("user-{}", &hex::encode(hash)[..12])
+    }
+
+    fn anonymize(&self, config: &Config, message: &Message) -> Message {
+        Message {
+            is_room: message.is_room,
+            is_memtioned: message.is_memtioned,
+            room_id: self.anonymize_id(config, &message.room_id),
+            content: self.redactor.redact(&message.content).into_owned(),
+            sender_id: self.anonymize_id(config, &message.sender_id),
+            message_id: String::new(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for TrafficRecorder {
+    fn name(&self) -> &'static str {
+        "traffic_recorder"
+    }
+
+    // 排在 RoomFilter 之后，只记录会被处理的消息
+    async fn after(&self, req: &Request, resp: &mut MessageResp) {
+        let Some(ctx) = &req.ctx else {
+            return;
+        };
+        self.writer.append(&TrafficEntry::new(self.anonymize(&ctx.config, &req.message), resp));
+    }
+}
+
+/// 依次把消息交给 ProxyService，返回实际的回复，和 entries 一一对应
+pub async fn replay_traffic(proxy: &impl Proxy, entries: &[TrafficEntry]) -> Result<Vec<TrafficEntry>> {
+    let mut actual = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let resp = proxy.on_message(tonic::Request::new(entry.message.clone())).await?.into_inner();
+        actual.push(TrafficEntry::new(entry.message.clone(), &resp));
+    }
+    Ok(actual)
+}
+
+/// expected 中的 {..} 匹配任意文本，其余部分需要完全一致
+pub fn golden_match(expected: &str, actual: &str) -> bool {
+    let mut parts = expected.split(WILDCARD);
+    let Some(mut rest) = actual.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// 和 golden 对比，返回每条不一致的说明，全部一致时为空
+pub fn compare(expected: &[TrafficEntry], actual: &[TrafficEntry]) -> Vec<String> {
+    let mut diffs = Vec::new();
+    if expected.len() != actual.len() {
+        diffs.push(format!("expected {} entries, got {}", expected.len(), actual.len()));
+    }
+    for (i, (e, a)) in expected.iter().zip(actual).enumerate() {
+        if e.code != a.code || !golden_match(&e.response, &a.response) {
+            diffs.push(format!(
+                "#{} {:?}: expected [{}] {:?}, got [{}] {:?}",
+                i + 1,
+                e.message.content,
+                e.code,
+                e.response,
+                a.code,
+                a.response
+            ));
+        }
+    }
+    diffs
+}
+
+/// 上游接口的一次请求和响应，不包含请求头和请求体
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exchange {
+    /// upstream 中的字段名，GPT 为 gpt
+    pub provider: String,
+    pub method: String,
+    /// 路径和去掉易变、敏感参数后的 query
+    pub path: String,
+    pub status: u16,
+    pub body: String,
+}
+
+type ExchangeKey = (String, String, String);
+
+enum Mode {
+    // 同一个请求录了多次时按顺序轮流返回
+    Replay(Mutex<HashMap<ExchangeKey, VecDeque<Exchange>>>),
+    Record {
+        targets: HashMap<String, String>,
+        client: reqwest::Client,
+        writer: JsonlWriter,
+    },
+}
+
+struct Inner {
+    mode: Mode,
+    misses: Mutex<Vec<String>>,
+}
+
+/// 本地的上游替身，config 中的上游地址改成 http://<addr>/<provider> 后：
+/// replay 模式按录制的内容返回，record 模式转发到真实地址并把结果追加到文件
+pub struct UpstreamServer {
+    addr: SocketAddr,
+    inner: Arc<Inner>,
+}
+
+// 可以重定向的上游地址，名字即 Exchange.provider
+fn upstreams(config: &mut Config) -> [(&'static str, &mut String); 7] {
+    let upstream = &mut config.upstream;
+    [
+        ("k780", &mut upstream.k780),
+        ("okx", &mut upstream.okx),
+        ("binance", &mut upstream.binance),
+        ("tanshu", &mut upstream.tanshu),
+        ("juhe", &mut upstream.juhe),
+        ("aceodds", &mut upstream.aceodds),
+        ("gpt", &mut config.gpt_api),
+    ]
+}
+
+// 去掉 IGNORED_PARAMS，其余参数排序，路径保持 url 编码
+fn normalize(path_and_query: &str) -> String {
+    let Ok(mut url) = reqwest::Url::parse(&format!("http://upstream{path_and_query}")) else {
+        return path_and_query.to_string();
+    };
+    let mut pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| !IGNORED_PARAMS.contains(&k.as_ref()))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    pairs.sort();
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
+}
+
+fn key(provider: &str, method: &str, path: &str) -> ExchangeKey {
+    (provider.to_string(), method.to_uppercase(), normalize(path))
+}
+
+impl UpstreamServer {
+    pub async fn replay(exchanges: Vec<Exchange>) -> Result<Self> {
+        let mut recorded: HashMap<ExchangeKey, VecDeque<Exchange>> = HashMap::new();
+        for exchange in exchanges {
+            recorded
+                .entry(key(&exchange.provider, &exchange.method, &exchange.path))
+                .or_default()
+                .push_back(exchange);
+        }
+        UpstreamServer::start(Mode::Replay(Mutex::new(recorded))).await
+    }
+
+    /// 转发到 config 中的真实地址，请求和响应追加到 path；按 upstream 配置走代理
+    pub async fn record(config: &Config, path: impl AsRef<Path>) -> Result<Self> {
+        let mut config = config.clone();
+        let targets = upstreams(&mut config)
+            .into_iter()
+            .map(|(provider, url)| (provider.to_string(), url.clone()))
+            .collect();
+        UpstreamServer::start(Mode::Record {
+            targets,
+            client: upstream::build_client(&config)?,
+            writer: JsonlWriter::open(path.as_ref())?,
+        })
+        .await
+    }
+
+    async fn start(mode: Mode) -> Result<Self> {
+        let inner = Arc::new(Inner {
+            mode,
+            misses: Mutex::new(Vec::new()),
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let app = Router::new().fallback(serve).with_state(inner.clone());
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, app).await {
+                error!("upstream server failed, err: {:?}", e);
+            }
+        });
+        Ok(UpstreamServer { addr, inner })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// 把 config 中的上游地址都指向这个服务
+    pub fn redirect(&self, config: &mut Config) {
+        for (provider, url) in upstreams(config) {
+            *url = format!("http://{}/{}", self.addr, provider);
+        }
+    }
+
+    /// replay 模式下没有录制过的请求
+    pub fn misses(&self) -> Vec<String> {
+        self.inner.misses.lock().unwrap().clone()
+    }
+}
+
+async fn serve(State(inner): State<Arc<Inner>>, method: Method, uri: Uri, headers: HeaderMap, body: Bytes) -> Response {
+    let path = uri.path().trim_start_matches('/');
+    let (provider, rest) = path.split_once('/').unwrap_or((path, ""));
+    let rest = match uri.query() {
+        Some(query) => format!("/{rest}?{query}"),
+        None => format!("/{rest}"),
+    };
+    match &inner.mode {
+        Mode::Replay(recorded) => {
+            let key = key(provider, method.as_str(), &rest);
+            let exchange = recorded.lock().unwrap().get_mut(&key).and_then(|queue| {
+                let exchange = queue.pop_front()?;
+                queue.push_back(exchange.clone());
+                Some(exchange)
+            });
+            match exchange {
+                Some(exchange) => {
+                    let status = StatusCode::from_u16(exchange.status).unwrap_or(StatusCode::BAD_GATEWAY);
+                    (status, exchange.body).into_response()
+                }
+                None => {
+                    let miss = format!("{} /{}{}", key.1, key.0, key.2);
+                    error!("no recorded exchange for {}", miss);
+                    inner.misses.lock().unwrap().push(miss.clone());
+                    (StatusCode::BAD_GATEWAY, format!("no recorded exchange for {miss}")).into_response()
+                }
+            }
+        }
+        Mode::Record { targets, client, writer } => {
+            let Some(target) = targets.get(provider) else {
+                return (StatusCode::NOT_FOUND, format!("unknown provider {provider}")).into_response();
+            };
+            let mut req = client
+                .request(method.clone(), format!("{}{}", target.trim_end_matches('/'), rest))
+                .body(body);
+            for name in [header::CONTENT_TYPE, header::ACCEPT, header::AUTHORIZATION] {
+                if let Some(value) = headers.get(&name) {
+                    req = req.header(name, value);
+                }
+            }
+            let resp = match req.send().await {
+                Ok(resp) => resp,
+                Err(e) => return (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+            };
+            let status = resp.status();
+            let content_type = resp.headers().get(header::CONTENT_TYPE).cloned();
+            let body = match resp.text().await {
+                Ok(body) => body,
+                Err(e) => return (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+            };
+            writer.append(&Exchange {
+                provider: provider.to_string(),
+                method: method.to_string(),
+                path: normalize(&rest),
+                status: status.as_u16(),
+                body: body.clone(),
+            });
+            let mut response = (status, body).into_response();
+            if let Some(content_type) = content_type {
+                response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+            }
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+fn testdata(name: &str) -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/replay").join(name)
+}
+
+#[test]
+fn test_golden_match() {
+    assert!(golden_match("hello", "hello"));
+    assert!(!golden_match("hello", "hello!"));
+    assert!(golden_match("可用指令{..}", "可用指令列表"));
+    assert!(golden_match("a{..}b{..}c", "a1b2c"));
+    assert!(!golden_match("a{..}a", "a"));
+    assert!(golden_match("{..}", ""));
+
+    assert_eq!(
+        normalize("/?app=finance.globalindex&inxids=1114&appkey=1&sign=2&format=json"),
+        "/?app=finance.globalindex&format=json&inxids=1114"
+    );
+    assert_eq!(normalize("/laohuangli/d?date=2024-01-01&key=k"), "/laohuangli/d");
+    assert_eq!(normalize("/zh-cn/足球.html"), "/zh-cn/%E8%B6%B3%E7%90%83.html");
+}
+
+/// 用 testdata/replay 中录制的流量和上游响应回放，设置 UPDATE_GOLDEN=1 时用实际回复覆盖 golden
+#[tokio::test]
+async fn test_replay_golden() {
+    use crate::dispatch::Dispatcher;
+    use crate::grpc::ProxyService;
+    use crate::handler::HandlerMgr;
+    use crate::state::BotState;
+
+    let upstream = UpstreamServer::replay(read_jsonl(testdata("upstream.jsonl")).unwrap()).await.unwrap();
+    let mut config = Config::builder().file(testdata("config.toml")).unwrap().build().unwrap();
+    upstream.redirect(&mut config);
+    let dir = crate::testing::TempDir::new("traffic");
+    let recorded = dir.join("traffic.jsonl");
+    let recorder = TrafficRecorder::open(&recorded, &config).unwrap();
+    let dispatcher = Dispatcher::new(Arc::new(BotState::new(config).unwrap()), HandlerMgr::builtin(), 4).middleware(recorder);
+    let proxy = ProxyService::new(Arc::new(dispatcher));
+
+    let golden: Vec<TrafficEntry> = read_jsonl(testdata("traffic.jsonl")).unwrap();
+    let actual = replay_traffic(&proxy, &golden).await.unwrap();
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        write_jsonl(testdata("traffic.jsonl"), &actual).unwrap();
+        return;
+    }
+    let diffs = compare(&golden, &actual);
+    assert!(diffs.is_empty(), "golden mismatch:\n{}", diffs.join("\n"));
+    assert!(upstream.misses().is_empty(), "{:?}", upstream.misses());
+
+    // 只记录了配置中的群和私聊，群里其他人的 id 被替换，密钥被去掉
+    let traffic: Vec<TrafficEntry> = read_jsonl(&recorded).unwrap();
+    assert!(traffic.iter().all(|e| e.message.room_id.is_empty() || e.message.room_id == "room"));
+    let group = traffic.iter().find(|e| e.message.is_room).unwrap();
+    assert!(group.message.sender_id.starts_with("user-") && group.message.sender_id != "bob");
+    let direct = traffic.iter().find(|e| !e.message.is_room).unwrap();
+    assert_eq!(direct.message.sender_id, "alice");
+    assert!(traffic.iter().all(|e| !e.message.content.contains("sk-replay-token")));
+}
+
+#[tokio::test]
+async fn test_record_upstream() {
+    let exchanges = read_jsonl(testdata("upstream.jsonl")).unwrap();
+    let replay = UpstreamServer::replay(exchanges).await.unwrap();
+    let mut config = Config::default();
+    replay.redirect(&mut config);
+
+    // 录制时转发到回放服务，结果应该和回放的内容一致
+    let dir = crate::testing::TempDir::new("upstream");
+    let path = dir.join("upstream.jsonl");
+    let recorder = UpstreamServer::record(&config, &path).await.unwrap();
+    recorder.redirect(&mut config);
+    let url = format!("{}/laohuangli/d?date=2024-01-01&key=juhekey", config.upstream.juhe);
+    let body = reqwest::get(&url).await.unwrap().text().await.unwrap();
+    assert!(body.contains("祭祀"));
+    let resp = reqwest::get(format!("{}/nothing", config.upstream.okx)).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_GATEWAY);
+
+    let recorded: Vec<Exchange> = read_jsonl(&path).unwrap();
+    assert_eq!(recorded.len(), 2);
+    assert_eq!(recorded[0].provider, "juhe");
+    assert_eq!(recorded[0].path, "/laohuangli/d");
+    assert_eq!(recorded[0].body, body);
+    assert_eq!(recorded[1].status, 502);
+    assert_eq!(replay.misses(), vec!["GET /okx/nothing"]);
+}
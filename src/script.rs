@@ -0,0 +1,269 @@
+use anyhow::Result;
+use chrono::Local;
+use log::{info, warn};
+use rand::seq::IndexedRandom;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::config::{Config, ScriptAction, ScriptCommand, read_file};
+use crate::error::Error;
+use crate::handler::{Context, Handler, HandlerMgr, Trigger};
+use crate::upstream;
+
+/// 由配置定义的简单指令
+pub struct ScriptHandler {
+    script: ScriptCommand,
+}
+
+impl ScriptHandler {
+    pub fn new(script: ScriptCommand) -> Self {
+        ScriptHandler { script }
+    }
+
+    fn prompts(&self) -> Vec<&str> {
+        if self.script.prompts.is_empty() {
+            vec![self.script.name.as_str()]
+        } else {
+            self.script.prompts.iter().map(|p| p.as_str()).collect()
+        }
+    }
+
+    // / 指令去掉指令本身，自由文本触发时为整条消息
+    fn args<'a>(&self, msg: &'a str) -> &'a str {
+        self.prompts()
+            .into_iter()
+            .find_map(|p| strip_prompt(msg, p))
+            .unwrap_or(msg)
+    }
+}
+
+fn strip_prompt<'a>(msg: &'a str, prompt: &str) -> Option<&'a str> {
+    msg.strip_prefix(prompt)
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+        .map(|rest| rest.trim())
+}
+
+fn render(template: &str, ctx: &Context, args: &str, value: Option<&str>) -> String {
+    render_with(template, ctx, args, value, str::to_string)
+}
+
+// 从左到右只替换一遍，替换进去的内容里即使有 {value} 之类的占位符也不会再被展开
+fn render_with(template: &str, ctx: &Context, args: &str, value: Option<&str>, f: fn(&str) -> String) -> String {
+    let date = Local::now().format("%Y-%m-%d").to_string();
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered += &rest[..start];
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let replacement = match &rest[1..end] {
+            "sender" => Some(ctx.sender_id.as_str()),
+            "chat" => Some(ctx.chat.id.as_str()),
+            "date" => Some(date.as_str()),
+            "args" => Some(args),
+            "value" => value,
+            _ => None,
+        };
+        match replacement {
+            Some(v) => {
+                rendered += &f(v);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered + rest
+}
+
+// 除了 RFC 3986 的非保留字符都做百分号编码，占位符的内容不能改写路径和参数
+fn encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded += &format!("%{b:02X}");
+        }
+    }
+    encoded
+}
+
+// url 模板中的占位符都编码；. 和 .. 编码后仍然是上一级目录，直接拒绝
+fn render_url(template: &str, ctx: &Context, args: &str) -> Result<String> {
+    if [args, ctx.sender_id.as_str(), ctx.chat.id.as_str()].iter().any(|v| matches!(*v, "." | "..")) {
+        Err(Error::ParamError(format!("invalid argument {args}")))?
+    }
+    Ok(render_with(template, ctx, args, None, encode))
+}
+
+async fn fetch(ctx: &Context, url: &str, pointer: &str) -> Result<String> {
+    let req = ctx.state.http().get(url).timeout(ctx.config.upstream.timeout("script"));
+    let json = upstream::json(req).await?;
+    let value = json
+        .pointer(pointer)
+        .ok_or(Error::ResultError("pointer not found"))?;
+    Ok(match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    })
+}
+
+#[async_trait::async_trait]
+impl Handler for ScriptHandler {
+    fn name(&self) -> &str {
+        &self.script.name
+    }
+
+    fn matches(&self, msg: &str) -> bool {
+        self.prompts().into_iter().any(|p| strip_prompt(msg, p).is_some())
+    }
+
+    fn triggers(&self) -> Vec<Trigger> {
+        if self.script.keywords.is_empty() {
+            return Vec::new();
+        }
+        let keywords: Vec<&str> = self.script.keywords.iter().map(|k| k.as_str()).collect();
+        vec![Trigger::keywords(&keywords, 0)]
+    }
+
+    async fn on_message(&self, ctx: &Context, msg: &str) -> Result<String> {
+        info!("script {} msg: {}", self.script.name, msg);
+        let args = self.args(msg);
+        match &self.script.action {
+            ScriptAction::Text { text } => Ok(text.clone()),
+            ScriptAction::Random { choices } => {
+                let choice = choices
+                    .choose(&mut rand::rng())
+                    .ok_or_else(|| Error::ConfigError(format!("script {} has no choices", self.script.name)))?;
+                Ok(render(choice, ctx, args, None))
+            }
+            ScriptAction::Template { template } => Ok(render(template, ctx, args, None)),
+            ScriptAction::Http { url, pointer, template } => {
+                let url = render_url(url, ctx, args)?;
+                let value = ctx.state.track(&self.script.name, fetch(ctx, &url, pointer)).await?;
+                Ok(match template {
+                    Some(template) => render(template, ctx, args, Some(&value)),
+                    None => value,
+                })
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ScriptsFile {
+    #[serde(default)]
+    scripts: Vec<ScriptCommand>,
+}
+
+/// 注册配置和 scripts_file 中的全部指令，和已有指令重名的会被跳过
+pub fn register_scripts(mgr: &mut HandlerMgr, config: &Config) -> Result<usize> {
+    let mut scripts = config.scripts.clone();
+    if let Some(path) = &config.scripts_file {
+        let file: ScriptsFile =
+            serde_json::from_value(read_file(path)?).map_err(|e| Error::ConfigError(e.to_string()))?;
+        scripts.extend(file.scripts);
+    }
+    let mut count = 0;
+    for script in scripts {
+        if mgr.handler_names().contains(&script.name.as_str()) {
+            warn!("script {} conflicts with an existing handler, skipped", script.name);
+            continue;
+        }
+        mgr.register_handler(ScriptHandler::new(script));
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[tokio::test]
+async fn test_scripts() {
+    use std::sync::Arc;
+
+    use crate::config::ChatConfig;
+    use crate::state::BotState;
+
+    // 只会回复一个固定 json 的本地 http 服务
+    let app = axum::Router::new().fallback(|uri: axum::http::Uri| async move {
+        axum::Json(serde_json::json!({"data": {"path": uri.to_string(), "temp": 21.5}}))
+    });
+    let addr = crate::testing::serve(app).await;
+
+    let dir = crate::testing::TempDir::new("scripts");
+    let file = dir.join("scripts.toml");
+    std::fs::write(
+        &file,
+        format!(
+            r#"
+[[scripts]]
+name = "weather"
+type = "http"
+url = "http://{addr}/weather/{{args}}"
+pointer = "/data/temp"
+template = "{{args}} {{value}} 度"
+
+[[scripts]]
+name = "path"
+type = "http"
+url = "http://{addr}/p"
+pointer = "/data/path"
+
+[[scripts]]
+name = "echo"
+type = "http"
+url = "http://{addr}/e/{{args}}?q={{args}}"
+pointer = "/data/path"
+"#
+        ),
+    )
+    .unwrap();
+
+    let config = Config::builder()
+        .set("room_id", "room")
+        .set("rate_limit.enabled", "false")
+        .set(
+            "scripts",
+            r#"[
+                {"name": "ping", "type": "text", "text": "pong {args}"},
+                {"name": "pick", "prompts": ["选", "pick"], "type": "random", "choices": ["{sender}"]},
+                {"name": "hello", "keywords": ["早上好"], "type": "template", "template": "{sender} 早，今天是 {date}，{args}"},
+                {"name": "help", "type": "text", "text": "conflict"}
+            ]"#,
+        )
+        .set("scripts_file", file.to_str().unwrap())
+        .build()
+        .unwrap();
+    let mut mgr = HandlerMgr::new();
+    mgr.register_handler(crate::help::Help::new());
+    assert_eq!(register_scripts(&mut mgr, &config).unwrap(), 6);
+
+    let state = Arc::new(BotState::new(config).unwrap());
+    let mut chat = ChatConfig::new("room");
+    chat.triggers = Some(vec![String::from("hello")]);
+    let ctx = state.context(chat, "alice").await;
+    let run = |msg: &'static str| {
+        let (mgr, ctx) = (&mgr, &ctx);
+        async move { mgr.match_handler(ctx, msg).await.unwrap().unwrap() }
+    };
+    assert_eq!(run("ping x").await, "pong {args}");
+    assert_eq!(run("选").await, "alice");
+    assert_eq!(run("pick a b").await, "alice");
+    assert!(run("help").await.starts_with("可用指令列表"));
+    assert_eq!(run("weather 北京").await, "北京 21.5 度");
+    // 参数中的占位符原样保留，不会再被展开
+    assert_eq!(run("weather {value}").await, "{value} 21.5 度");
+    assert_eq!(run("path").await, "/p");
+    // 参数在 url 中编码，不能改写路径和参数
+    assert_eq!(run("echo a&b=c#x").await, "/e/a%26b%3Dc%23x?q=a%26b%3Dc%23x");
+    assert_eq!(run("echo ../admin").await, "/e/..%2Fadmin?q=..%2Fadmin");
+    assert!(mgr.match_handler(&ctx, "echo ..").await.is_err());
+    let today = Local::now().format("%Y-%m-%d").to_string();
+    let reply = mgr.match_trigger(&ctx, "大家早上好").await.unwrap().unwrap();
+    assert_eq!(reply, format!("alice 早，今天是 {today}，大家早上好"));
+    assert!(mgr.match_handler(&ctx, "pinged").await.unwrap().is_none());
+}
@@ -1,150 +1,160 @@
-use log4rs::append::rolling_file::RollingFileAppender;
-use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
-use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
-use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
-use std::{fs::File, sync::Arc};
-use tokio::sync::Mutex;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
-use log4rs::append::file::FileAppender;
-use log4rs::config::{Appender, Config, Root};
-use log4rs::encode::pattern::PatternEncoder;
-
-use crate::proxy::{
-    Message, MessageResp,
-    proxy_server::{Proxy, ProxyServer},
-};
-use tonic::{Request, Response, Status, transport::Server};
+use crate::proxy::proxy_server::ProxyServer;
+use tonic::transport::Server;
 use wechat_bot_core::{
-    basic_market_info::BasicMakertInfo, config::get_config, gamble::Gamble, gpt::GPTProxy,
-    handler::HandlerMgr, huangli::HuangLi, proxy::RespCode, *,
-    help::Help,
+    config::{Args, Config}, dispatch::Dispatcher, grpc::{AuthInterceptor, ProxyService}, handler::HandlerMgr,
+    http as http_server, adapter::ChatAdapter, matrix::MatrixAdapter, telegram::TelegramAdapter,
+    reflection::{ReflectionService, ServerReflectionServer}, state::BotState, storage::Storage, *,
 };
 // Import the generated proto-rust file into a module
 
-use log::{LevelFilter, error, info};
+use clap::Parser;
+use log::{error, info};
+use tonic_health::ServingStatus;
 
-#[derive(Default)]
-pub struct ProxyService {
-    handlers: Arc<Mutex<HandlerMgr>>,
-}
-impl ProxyService {
-    pub async fn register_handlers(&mut self) {
-        let mut handlers = self.handlers.lock().await;
-        handlers
-            .register_handler(Arc::new(Mutex::new(BasicMakertInfo::new())))
-            .await;
-        handlers
-            .register_handler(Arc::new(Mutex::new(Gamble::new())))
-            .await;
-        handlers
-            .register_handler(Arc::new(Mutex::new(HuangLi::new())))
-            .await;
-        handlers
-            .register_handler(Arc::new(Mutex::new(Help::new())))
-            .await;
-    }
-}
-#[tonic::async_trait]
-impl Proxy for ProxyService {
-    async fn on_message(
-        &self,
-        req: tonic::Request<Message>,
-    ) -> std::result::Result<tonic::Response<MessageResp>, tonic::Status> {
-        // filter
-        let msg = req.get_ref();
-        let is_target = msg.is_room
-            && (msg.room_id == get_config().room_id_dev || msg.room_id == get_config().room_id)
-            && msg.is_memtioned;
-        if !is_target {
-            let resp = MessageResp {
-                code: RespCode::Ignore.into(),
-                response: String::from(""),
-            };
-            return Ok(Response::new(resp));
-        }
-        let content = msg.content.trim();
-        // Follow the instruction
-        if content.starts_with("/") {
-            let hs = self.handlers.lock().await;
-            match hs
-                .match_handler(&content.chars().skip(1).collect::<String>())
-                .await
-            {
-                Ok(v) => {
-                    let resp = MessageResp {
-                        code: RespCode::Ok.into(),
-                        response: v,
-                    };
-                    return Ok(Response::new(resp));
-                }
-                Err(e) => {
-                    error!("failed to execute instruction, err: {:?}", e);
-                    let resp = MessageResp {
-                        code: RespCode::Ok.into(),
-                        response: String::from("哦豁"),
-                    };
-                    return Ok(Response::new(resp));
-                }
-            }
-        }
-        // Respond to the message
-        let mut gpt_proxy = GPTProxy::new(
-            get_config().model.clone(),
-            get_config().user_id.clone(),
-            get_config().gpt_api.clone(),
-            get_config().gpt_token.clone(),
-            get_config().tieba_pre_set.clone(),
-        );
+const PROXY_SERVICE: &str = <ProxyServer<ProxyService> as tonic::server::NamedService>::NAME;
 
-        match gpt_proxy.query(content.to_string()).await {
-            Ok(v) => {
-                let resp = MessageResp {
-                    code: RespCode::Ok.into(),
-                    response: v,
-                };
-                return Ok(Response::new(resp));
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("failed to listen for ctrl-c, err: {:?}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
             }
             Err(e) => {
-                let resp = MessageResp {
-                    code: RespCode::Ok.into(),
-                    response: String::from("哦豁"),
-                };
-                return Ok(Response::new(resp));
+                error!("failed to listen for SIGTERM, err: {:?}", e);
+                std::future::pending::<()>().await;
             }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
 }
 
-fn init_logger() {
-    let log_roller = FixedWindowRoller::builder()
-        .build("logs/archive/app_{}.log", 5) // 保留5个历史文件
-        .unwrap();
-    let log_trigger = SizeTrigger::new(1024 * 1024); // 1MB触发滚动
-    let log_policy = CompoundPolicy::new(Box::new(log_trigger), Box::new(log_roller));
-
-    let logfile = RollingFileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d} {l} - {m}{n}")))
-        .build("logs/app.log", Box::new(log_policy))
-        .unwrap();
-    let config = Config::builder()
-        .appender(Appender::builder().build("logfile", Box::new(logfile)))
-        .build(Root::builder().appender("logfile").build(LevelFilter::Info))
-        .unwrap();
-    // 初始化日志系统
-    log4rs::init_config(config).unwrap();
-}
-
 // Runtime to run our server
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::1]:50051".parse()?;
-    let mut proxy = ProxyService::default();
-    proxy.register_handlers().await;
-    init_logger();
-    Server::builder()
-        .add_service(ProxyServer::new(proxy))
-        .serve(addr)
+    let args = Args::parse();
+    let mut config = Config::from_args(&args).unwrap_or_else(|e| {
+        eprintln!("Failed to load config: {e:#}");
+        std::process::exit(1);
+    });
+    // 尽早初始化，加载插件和指令时的日志也能记录下来
+    let log_guard = logging::init(&config)?;
+    // 录制上游时，所有上游请求经过本地的转发服务，重新加载的配置同样转发
+    let upstream_recorder = match &config.record.upstream {
+        Some(path) => {
+            let recorder = replay::UpstreamServer::record(&config, path).await?;
+            info!("recording upstream exchanges to {} via {}", path.display(), recorder.addr());
+            recorder.redirect(&mut config);
+            Some(recorder)
+        }
+        None => None,
+    };
+    let storage = Storage::open(&config.storage.path)?;
+    let mut handlers = HandlerMgr::builtin();
+    script::register_scripts(&mut handlers, &config)?;
+    plugin::register_plugins(&mut handlers, &config.plugins)?;
+    // 代理等配置有误时直接退出
+    let state = BotState::new(config)?
+        .with_storage(Arc::new(storage))?
+        .with_reloader(move || {
+            let mut config = Config::from_args(&args)?;
+            if let Some(recorder) = &upstream_recorder {
+                recorder.redirect(&mut config);
+            }
+            Ok(config)
+        });
+    let config = state.config().await;
+    let server = config.server.clone();
+    let http = config.http.clone();
+    let addr: SocketAddr = server.listen.parse()?;
+    let state = Arc::new(state);
+    let mut dispatcher = Dispatcher::new(state.clone(), handlers, server.max_in_flight);
+    if let Some(path) = &config.record.traffic {
+        info!("recording traffic to {}", path.display());
+        dispatcher = dispatcher.middleware(replay::TrafficRecorder::open(path, &config)?);
+    }
+    let dispatcher = Arc::new(dispatcher);
+    let proxy = ProxyService::new(dispatcher.clone());
+
+    let (health, health_service) = tonic_health::server::health_reporter();
+    health.set_service_status(PROXY_SERVICE, ServingStatus::Serving).await;
+    let reflection = ReflectionService::new(&[reflection::FILE_DESCRIPTOR_SET, tonic_health::pb::FILE_DESCRIPTOR_SET])?;
+
+    let mut builder = Server::builder();
+    if let Some(tls) = &server.tls {
+        builder = builder.tls_config(grpc::tls_config(tls)?)?;
+    }
+    // gRPC、http 服务和各个聊天网络的接入共用一个退出信号
+    let (stop_tx, stop_rx) = tokio::sync::watch::channel(false);
+    let mut tasks = Vec::new();
+    if let Some(listen) = &http.listen {
+        let listener = tokio::net::TcpListener::bind(listen).await?;
+        info!("Starting HTTP Server on {}", listen);
+        let app = http_server::router(dispatcher.clone(), &http, server.auth_token.clone());
+        let mut stop = stop_rx.clone();
+        tasks.push(tokio::spawn(async move {
+            let shutdown = async move {
+                stop.wait_for(|s| *s).await.ok();
+            };
+            if let Err(e) = axum::serve(listener, app).with_graceful_shutdown(shutdown).await {
+                error!("http server failed, err: {:?}", e);
+            }
+        }));
+    }
+    if let Some(telegram) = &config.telegram {
+        // 连接 Telegram 失败时在后台重试，不影响其它服务启动
+        let (telegram, client, dispatcher, mut stop) =
+            (telegram.clone(), state.http().clone(), dispatcher.clone(), stop_rx.clone());
+        tasks.push(tokio::spawn(async move {
+            if let Some(telegram) = TelegramAdapter::connect_with_retry(telegram, client, &mut stop).await {
+                adapter::run(Arc::new(telegram), dispatcher, stop).await;
+            }
+        }));
+    }
+    let mut adapters: Vec<Arc<dyn ChatAdapter>> = Vec::new();
+    if let Some(matrix) = &config.matrix {
+        adapters.push(Arc::new(MatrixAdapter::new(matrix.clone(), state.http().clone())));
+    }
+    for chat in adapters {
+        tasks.push(tokio::spawn(adapter::run(chat, dispatcher.clone(), stop_rx.clone())));
+    }
+
+    info!("Starting gRPC Server on {}, tls: {}", addr, server.tls.is_some());
+    // 收到退出信号后不再接受新请求，等待处理中的请求完成
+    let shutdown = async {
+        shutdown_signal().await;
+        info!("shutting down, draining in-flight requests");
+        health.set_service_status(PROXY_SERVICE, ServingStatus::NotServing).await;
+        stop_tx.send(true).ok();
+    };
+    builder
+        .add_service(health_service)
+        .add_service(ServerReflectionServer::new(reflection))
+        .add_service(ProxyServer::with_interceptor(proxy, AuthInterceptor::new(server.auth_token)))
+        .serve_with_shutdown(addr, shutdown)
         .await?;
-    info!("Starting gRPC Server...");
+    for task in tasks {
+        task.await.ok();
+    }
+
+    if let Err(e) = state.storage().call(Storage::flush).await {
+        error!("failed to flush storage, err: {:?}", e);
+    }
+    info!("gRPC Server stopped");
+    drop(log_guard);
     Ok(())
 }
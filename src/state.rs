@@ -0,0 +1,411 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::config::{BreakerConfig, ChatConfig, Config};
+use crate::error::{Error, ErrorKind, classify};
+use crate::handler::Context;
+use crate::metrics::Metrics;
+use crate::ratelimit::RateLimiter;
+use crate::storage::Storage;
+use crate::upstream;
+
+type Reloader = Box<dyn Fn() -> Result<Config> + Send + Sync>;
+
+/// 管理员在运行时对单个群聊/私聊做的修改，保存在存储中
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ChatOverride {
+    pub enabled: HashSet<String>,
+    pub disabled: HashSet<String>,
+    pub model: Option<String>,
+    pub preset: Option<String>,
+    pub muted_until: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct HandlerStats {
+    pub calls: u64,
+    pub errors: u64,
+    pub total_ms: u128,
+}
+
+/// 上游的熔断状态
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Breaker {
+    #[default]
+    Closed,
+    /// 在此之前的调用直接失败
+    Open(DateTime<Local>),
+    /// 冷却结束后放行了一次试探，在此之前没有结果时再放行下一次
+    HalfOpen(DateTime<Local>),
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ProviderHealth {
+    pub successes: u64,
+    pub failures: u64,
+    /// 熔断期间被直接拒绝的调用
+    pub rejected: u64,
+    pub consecutive_failures: u32,
+    pub breaker: Breaker,
+    pub last_ok: Option<DateTime<Local>>,
+    pub last_error: Option<(DateTime<Local>, String)>,
+}
+
+/// 进程内共享的运行时状态
+pub struct BotState {
+    started_at: DateTime<Local>,
+    config: RwLock<Arc<Config>>,
+    reloader: Option<Reloader>,
+    chats: Mutex<HashMap<String, ChatOverride>>,
+    handler_stats: Mutex<HashMap<String, HandlerStats>>,
+    providers: Mutex<HashMap<String, ProviderHealth>>,
+    limiter: RateLimiter,
+    storage: Arc<Storage>,
+    metrics: Metrics,
+    http: reqwest::Client,
+}
+
+impl BotState {
+    const CHAT_OVERRIDE: &'static str = "chat_override";
+
+    /// 使用内存数据库，需要持久化时再调用 with_storage；代理等上游配置有误时返回错误，而不是退回到默认的客户端
+    pub fn new(config: Config) -> Result<Self> {
+        let http = upstream::build_client(&config)?;
+        Ok(BotState::new_with_client(config, http))
+    }
+
+    /// 与 new 相同，但所有上游请求使用调用方已经构建好的 http 客户端
+    pub fn new_with_client(config: Config, http: reqwest::Client) -> Self {
+        BotState {
+            started_at: Local::now(),
+            config: RwLock::new(Arc::new(config)),
+            reloader: None,
+            chats: Mutex::new(HashMap::new()),
+            handler_stats: Mutex::new(HashMap::new()),
+            providers: Mutex::new(HashMap::new()),
+            limiter: RateLimiter::new(),
+            storage: Arc::new(Storage::memory().expect("failed to open in-memory storage")),
+            metrics: Metrics::new(),
+            http,
+        }
+    }
+
+    /// 切换到指定存储，并恢复其中保存的会话修改
+    pub fn with_storage(mut self, storage: Arc<Storage>) -> Result<Self> {
+        let chats = storage.kv().list::<ChatOverride>(BotState::CHAT_OVERRIDE)?;
+        self.chats = Mutex::new(chats.into_iter().collect());
+        self.storage = storage;
+        Ok(self)
+    }
+
+    /// 设置 /admin reload 时重新读取配置的方式
+    pub fn with_reloader(mut self, reloader: impl Fn() -> Result<Config> + Send + Sync + 'static) -> Self {
+        self.reloader = Some(Box::new(reloader));
+        self
+    }
+
+    pub fn started_at(&self) -> DateTime<Local> {
+        self.started_at
+    }
+
+    pub fn limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    pub async fn config(&self) -> Arc<Config> {
+        self.config.read().await.clone()
+    }
+
+    pub async fn reload(&self) -> Result<Arc<Config>> {
+        let reloader = self
+            .reloader
+            .as_ref()
+            .ok_or(Error::ResultError("config reload is not supported"))?;
+        let config = Arc::new(reloader()?);
+        *self.config.write().await = config.clone();
+        Ok(config)
+    }
+
+    /// 为一条消息生成上下文，带上当前配置和该会话的运行时修改
+    pub async fn context(self: &Arc<Self>, chat: ChatConfig, sender_id: &str) -> Context {
+        let overrides = self.chat_override(&chat.id).await;
+        Context {
+            config: self.config().await,
+            state: self.clone(),
+            chat,
+            sender_id: sender_id.to_string(),
+            overrides,
+        }
+    }
+
+    pub async fn chat_override(&self, chat_id: &str) -> ChatOverride {
+        self.chats.lock().await.get(chat_id).cloned().unwrap_or_default()
+    }
+
+    pub async fn update_chat<F: FnOnce(&mut ChatOverride)>(&self, chat_id: &str, f: F) {
+        let mut chats = self.chats.lock().await;
+        let chat = chats.entry(chat_id.to_string()).or_default();
+        f(chat);
+        // 持有锁直到写完，保证同一会话的修改按顺序落盘
+        let (chat_id, chat) = (chat_id.to_string(), chat.clone());
+        let saved = self
+            .storage
+            .call(move |s| s.kv().set(BotState::CHAT_OVERRIDE, &chat_id, &chat, None))
+            .await;
+        if let Err(e) = saved {
+            error!("failed to save chat override, err: {:?}", e);
+        }
+    }
+
+    pub async fn is_muted(&self, chat_id: &str) -> bool {
+        self.chats
+            .lock()
+            .await
+            .get(chat_id)
+            .and_then(|c| c.muted_until)
+            .is_some_and(|until| until > Local::now())
+    }
+
+    pub async fn record_handler(&self, name: &str, ok: bool, elapsed_ms: u128) {
+        let mut stats = self.handler_stats.lock().await;
+        let s = stats.entry(name.to_string()).or_default();
+        s.calls += 1;
+        s.total_ms += elapsed_ms;
+        if !ok {
+            s.errors += 1;
+        }
+    }
+
+    pub async fn handler_stats(&self) -> Vec<(String, HandlerStats)> {
+        let mut stats: Vec<_> = self
+            .handler_stats
+            .lock()
+            .await
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    // 熔断打开时直接返回 CircuitOpen；冷却结束后放行一次试探
+    async fn admit(&self, provider: &str, breaker: &BreakerConfig) -> Result<()> {
+        if !breaker.enabled {
+            return Ok(());
+        }
+        let mut providers = self.providers.lock().await;
+        let p = providers.entry(provider.to_string()).or_default();
+        let (Breaker::Open(until) | Breaker::HalfOpen(until)) = p.breaker else {
+            return Ok(());
+        };
+        let now = Local::now();
+        if until <= now {
+            p.breaker = Breaker::HalfOpen(now + chrono::Duration::seconds(breaker.cooldown as i64));
+            return Ok(());
+        }
+        p.rejected += 1;
+        Err(Error::CircuitOpen {
+            provider: provider.to_string(),
+            seconds: (until - now).num_seconds().max(1) as u64,
+        })?
+    }
+
+    pub async fn record_provider<T>(&self, provider: &str, result: &Result<T>, breaker: &BreakerConfig) {
+        let mut providers = self.providers.lock().await;
+        let p = providers.entry(provider.to_string()).or_default();
+        let now = Local::now();
+        match result {
+            Ok(_) => {
+                p.successes += 1;
+                p.last_ok = Some(now);
+                p.consecutive_failures = 0;
+                p.breaker = Breaker::Closed;
+            }
+            Err(e) => {
+                p.failures += 1;
+                p.last_error = Some((now, e.to_string()));
+                // 参数错误不是上游的问题，不计入熔断
+                if classify(e) == ErrorKind::User {
+                    return;
+                }
+                p.consecutive_failures += 1;
+                let probe_failed = matches!(p.breaker, Breaker::HalfOpen(_));
+                if breaker.enabled && (probe_failed || p.consecutive_failures >= breaker.failures) {
+                    warn!("circuit for {} opened after {} failures", provider, p.consecutive_failures);
+                    p.breaker = Breaker::Open(now + chrono::Duration::seconds(breaker.cooldown as i64));
+                }
+            }
+        }
+    }
+
+    /// 执行一次上游调用并记录该上游的健康状况，熔断打开时不执行
+    pub async fn track<T>(&self, provider: &str, fut: impl Future<Output = Result<T>>) -> Result<T> {
+        let breaker = self.config().await.upstream.breaker.clone();
+        self.admit(provider, &breaker).await?;
+        let start = Instant::now();
+        let result = fut.await;
+        self.metrics.observe_upstream(provider, result.is_ok(), start.elapsed());
+        self.record_provider(provider, &result, &breaker).await;
+        result
+    }
+
+    /// 依次用 providers 执行 f，返回第一个成功的结果，都失败时返回最后一个错误
+    ///
+    /// providers 一般为 config.upstream.chain(provider)
+    pub async fn track_chain<'a, T, F, Fut>(&self, providers: &[&'a str], f: F) -> Result<T>
+    where
+        F: Fn(&'a str) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut last = None;
+        for provider in providers {
+            if let Some(e) = &last {
+                warn!("falling back to {}, err: {}", provider, e);
+            }
+            match self.track(provider, f(provider)).await {
+                Ok(v) => return Ok(v),
+                Err(e) => last = Some(e),
+            }
+        }
+        Err(last.unwrap_or_else(|| Error::ResultError("no upstream provider").into()))
+    }
+
+    /// 手动关闭熔断，返回该上游是否存在
+    pub async fn reset_provider(&self, provider: &str) -> bool {
+        let mut providers = self.providers.lock().await;
+        let Some(p) = providers.get_mut(provider) else {
+            return false;
+        };
+        p.consecutive_failures = 0;
+        p.breaker = Breaker::Closed;
+        true
+    }
+
+    pub async fn provider_health(&self) -> Vec<(String, ProviderHealth)> {
+        let mut providers: Vec<_> = self
+            .providers
+            .lock()
+            .await
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        providers.sort_by(|a, b| a.0.cmp(&b.0));
+        providers
+    }
+}
+
+#[tokio::test]
+async fn test_bot_state() {
+    // 代理配置有误时不会悄悄换成默认客户端
+    let config = Config::builder().set("room_id", "room").set("upstream.proxy", "not a proxy").build().unwrap();
+    assert!(BotState::new(config).is_err());
+
+    let config = Config::builder().set("room_id", "room").build().unwrap();
+    let state = Arc::new(BotState::new(config).unwrap().with_reloader(|| {
+        Config::builder().set("room_id", "room").set("model", "reloaded").build()
+    }));
+
+    state
+        .update_chat("room", |c| {
+            c.disabled.insert(String::from("help"));
+            c.muted_until = Some(Local::now() + chrono::Duration::minutes(5));
+        })
+        .await;
+    assert!(state.is_muted("room").await);
+    assert!(!state.is_muted("other").await);
+    let ctx = state.context(ChatConfig::new("room"), "user").await;
+    assert!(!ctx.command_enabled("help"));
+    assert!(ctx.command_enabled("market"));
+
+    state.track("okx", async { Err::<(), _>(Error::ResultError("down").into()) }).await.ok();
+    state.track("okx", async { Ok(()) }).await.unwrap();
+    let health = state.provider_health().await;
+    assert_eq!(health[0].1.successes, 1);
+    assert_eq!(health[0].1.failures, 1);
+    assert_eq!(health[0].1.breaker, Breaker::Closed);
+
+    assert_eq!(state.reload().await.unwrap().model, "reloaded");
+    assert_eq!(state.config().await.model, "reloaded");
+
+    // 会话修改保存在存储里，换一个 BotState 也能恢复
+    let config = Config::builder().set("room_id", "room").build().unwrap();
+    let restored = BotState::new(config).unwrap().with_storage(state.storage.clone()).unwrap();
+    assert!(restored.is_muted("room").await);
+    assert!(restored.chat_override("room").await.disabled.contains("help"));
+}
+
+#[tokio::test]
+async fn test_circuit_breaker() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let config = Config::builder()
+        .set("room_id", "room")
+        .set("upstream.breaker.failures", "2")
+        .set("upstream.breaker.cooldown", "60")
+        .set("upstream.fallbacks", r#"{"okx": ["binance"]}"#)
+        .build()
+        .unwrap();
+    let state = BotState::new(config).unwrap();
+    let calls = AtomicU32::new(0);
+    let down = || async {
+        calls.fetch_add(1, Ordering::SeqCst);
+        Err::<u32, _>(Error::ResultError("down").into())
+    };
+    // 参数错误不计入熔断
+    state.track("okx", async { Err::<(), _>(Error::ParamError(String::from("x")).into()) }).await.ok();
+    state.track("okx", down()).await.unwrap_err();
+    state.track("okx", down()).await.unwrap_err();
+    let e = state.track("okx", down()).await.unwrap_err();
+    assert!(matches!(e.downcast_ref::<Error>(), Some(Error::CircuitOpen { seconds, .. }) if *seconds > 50));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // 熔断时直接使用备用上游
+    let config = state.config().await;
+    let price = state
+        .track_chain(&config.upstream.chain("okx"), |p| async move { if p == "binance" { Ok(1) } else { Err(anyhow::anyhow!("down")) } })
+        .await
+        .unwrap();
+    assert_eq!(price, 1);
+    let health = state.provider_health().await;
+    assert_eq!(health[0].0, "binance");
+    assert_eq!((health[1].1.failures, health[1].1.rejected), (3, 2));
+
+    // 冷却结束后放行一次试探，失败则重新打开，成功则恢复
+    let expire = |state: &BotState| {
+        let mut providers = state.providers.try_lock().unwrap();
+        providers.get_mut("okx").unwrap().breaker = Breaker::Open(Local::now());
+    };
+    expire(&state);
+    state.track("okx", down()).await.unwrap_err();
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    assert!(matches!(state.provider_health().await[1].1.breaker, Breaker::Open(_)));
+    expire(&state);
+    state.track("okx", async { Ok(()) }).await.unwrap();
+    assert_eq!(state.provider_health().await[1].1.breaker, Breaker::Closed);
+
+    state.track("okx", down()).await.unwrap_err();
+    state.track("okx", down()).await.unwrap_err();
+    assert!(state.reset_provider("okx").await);
+    assert!(!state.reset_provider("nope").await);
+    state.track("okx", async { Ok(()) }).await.unwrap();
+}
@@ -0,0 +1,314 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Local, TimeZone, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::usage::{UsageRecord, UsageSummary};
+
+// 按顺序执行，已执行到第几条记录在 PRAGMA user_version 中，只能追加不能修改
+const MIGRATIONS: &[&str] = &[
+    // 1: 大模型用量
+    "CREATE TABLE usage (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        at INTEGER NOT NULL,
+        room TEXT NOT NULL,
+        user TEXT NOT NULL,
+        handler TEXT NOT NULL,
+        model TEXT NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL,
+        cost REAL NOT NULL
+    );
+    CREATE INDEX usage_at ON usage(at);",
+    // 2: 键值对，用于缓存和运行时状态，expires_at 为空表示不过期
+    "CREATE TABLE kv (
+        namespace TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        expires_at INTEGER,
+        PRIMARY KEY (namespace, key)
+    );",
+    // 3: 会话历史，目前没有使用，迁移只能追加所以保留
+    "CREATE TABLE history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        chat_id TEXT NOT NULL,
+        sender_id TEXT NOT NULL,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        at INTEGER NOT NULL
+    );
+    CREATE INDEX history_chat ON history(chat_id, id);",
+];
+
+/// 基于 SQLite 的本地存储，clone 后共用同一个连接
+#[derive(Clone)]
+pub struct Storage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Storage {
+    /// 作为 path 时打开内存数据库
+    pub const MEMORY: &'static str = ":memory:";
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path == Path::new(Storage::MEMORY) {
+            return Storage::memory();
+        }
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let conn = Connection::open(path)?;
+        // WAL 模式下读写互不阻塞，写入先进 -wal 文件，flush 时再合并回数据库文件
+        let mode: String = conn.query_row("PRAGMA journal_mode=WAL", [], |r| r.get(0))?;
+        if !mode.eq_ignore_ascii_case("wal") {
+            anyhow::bail!("failed to enable WAL on {}: journal_mode is {mode}", path.display());
+        }
+        Storage::from_conn(conn)
+    }
+
+    /// 内存数据库，进程退出后丢失
+    pub fn memory() -> Result<Self> {
+        Storage::from_conn(Connection::open_in_memory()?)
+    }
+
+    fn from_conn(mut conn: Connection) -> Result<Self> {
+        migrate(&mut conn)?;
+        Ok(Storage {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// SQLite 的读写会阻塞（WAL 模式下写入还要 fsync），异步代码中通过它放到阻塞线程池执行
+    pub async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Storage) -> Result<T> + Send + 'static,
+    {
+        let storage = self.clone();
+        tokio::task::spawn_blocking(move || f(&storage)).await?
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn schema_version(&self) -> Result<usize> {
+        let version: usize = self.conn().query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        Ok(version)
+    }
+
+    /// 退出前调用：清理过期数据并把 WAL 写回数据库文件
+    pub fn flush(&self) -> Result<()> {
+        self.kv().purge_expired()?;
+        let conn = self.conn();
+        // optimize 可能写入统计信息，要放在 checkpoint 之前
+        conn.execute_batch("PRAGMA optimize")?;
+        let busy: i64 = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |r| r.get(0))?;
+        if busy != 0 {
+            anyhow::bail!("wal checkpoint blocked by another connection");
+        }
+        Ok(())
+    }
+
+    pub fn usage(&self) -> UsageRepo<'_> {
+        UsageRepo { storage: self }
+    }
+
+    pub fn kv(&self) -> KvRepo<'_> {
+        KvRepo { storage: self }
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn to_millis(at: DateTime<Local>) -> i64 {
+    at.timestamp_millis()
+}
+
+fn from_millis(ms: i64) -> DateTime<Local> {
+    Utc.timestamp_millis_opt(ms)
+        .single()
+        .unwrap_or_default()
+        .with_timezone(&Local)
+}
+
+pub struct UsageRepo<'a> {
+    storage: &'a Storage,
+}
+
+impl UsageRepo<'_> {
+    pub fn insert(&self, r: &UsageRecord) -> Result<()> {
+        self.storage.conn().execute(
+            "INSERT INTO usage (at, room, user, handler, model, prompt_tokens, completion_tokens, cost)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                to_millis(r.at),
+                r.room,
+                r.user,
+                r.handler,
+                r.model,
+                r.prompt_tokens,
+                r.completion_tokens,
+                r.cost
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// [from, to) 区间内的记录
+    pub fn list(&self, from: DateTime<Local>, to: DateTime<Local>) -> Result<Vec<UsageRecord>> {
+        let conn = self.storage.conn();
+        let mut stmt = conn.prepare(
+            "SELECT at, room, user, handler, model, prompt_tokens, completion_tokens, cost
+             FROM usage WHERE at >= ?1 AND at < ?2 ORDER BY id",
+        )?;
+        let records = stmt
+            .query_map(params![to_millis(from), to_millis(to)], |row| {
+                Ok(UsageRecord {
+                    at: from_millis(row.get(0)?),
+                    room: row.get(1)?,
+                    user: row.get(2)?,
+                    handler: row.get(3)?,
+                    model: row.get(4)?,
+                    prompt_tokens: row.get(5)?,
+                    completion_tokens: row.get(6)?,
+                    cost: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    }
+
+    pub fn summary(&self, from: DateTime<Local>, to: DateTime<Local>) -> Result<UsageSummary> {
+        Ok(UsageSummary::from_records(&self.list(from, to)?))
+    }
+
+    pub fn cost(&self, from: DateTime<Local>, to: DateTime<Local>) -> Result<f64> {
+        let cost = self.storage.conn().query_row(
+            "SELECT COALESCE(SUM(cost), 0) FROM usage WHERE at >= ?1 AND at < ?2",
+            params![to_millis(from), to_millis(to)],
+            |r| r.get(0),
+        )?;
+        Ok(cost)
+    }
+}
+
+/// 按 namespace 分组的 json 键值对
+pub struct KvRepo<'a> {
+    storage: &'a Storage,
+}
+
+impl KvRepo<'_> {
+    pub fn get<T: DeserializeOwned>(&self, namespace: &str, key: &str) -> Result<Option<T>> {
+        let value: Option<String> = self
+            .storage
+            .conn()
+            .query_row(
+                "SELECT value FROM kv WHERE namespace = ?1 AND key = ?2
+                 AND (expires_at IS NULL OR expires_at > ?3)",
+                params![namespace, key, to_millis(Local::now())],
+                |r| r.get(0),
+            )
+            .optional()?;
+        match value {
+            Some(v) => Ok(Some(serde_json::from_str(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// ttl 为空表示不过期
+    pub fn set<T: Serialize>(&self, namespace: &str, key: &str, value: &T, ttl: Option<Duration>) -> Result<()> {
+        let expires_at = ttl.map(|ttl| to_millis(Local::now()) + ttl.as_millis() as i64);
+        self.storage.conn().execute(
+            "INSERT INTO kv (namespace, key, value, expires_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (namespace, key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+            params![namespace, key, serde_json::to_string(value)?, expires_at],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete(&self, namespace: &str, key: &str) -> Result<()> {
+        self.storage
+            .conn()
+            .execute("DELETE FROM kv WHERE namespace = ?1 AND key = ?2", params![namespace, key])?;
+        Ok(())
+    }
+
+    pub fn list<T: DeserializeOwned>(&self, namespace: &str) -> Result<Vec<(String, T)>> {
+        let conn = self.storage.conn();
+        let mut stmt = conn.prepare(
+            "SELECT key, value FROM kv WHERE namespace = ?1
+             AND (expires_at IS NULL OR expires_at > ?2) ORDER BY key",
+        )?;
+        let rows = stmt
+            .query_map(params![namespace, to_millis(Local::now())], |r| {
+                Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter()
+            .map(|(k, v)| Ok((k, serde_json::from_str(&v)?)))
+            .collect()
+    }
+
+    /// 清理已过期的条目，返回清理的数量
+    pub fn purge_expired(&self) -> Result<usize> {
+        let n = self.storage.conn().execute(
+            "DELETE FROM kv WHERE expires_at IS NOT NULL AND expires_at <= ?1",
+            params![to_millis(Local::now())],
+        )?;
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_storage() {
+    let dir = crate::testing::TempDir::new("storage");
+    let path = dir.join("bot.db");
+    let storage = Storage::open(&path).unwrap();
+    assert_eq!(storage.schema_version().unwrap(), MIGRATIONS.len());
+
+    let kv = storage.kv();
+    kv.set("cache", "a", &vec![1, 2, 3], None).unwrap();
+    kv.set("cache", "b", &String::from("gone"), Some(Duration::ZERO)).unwrap();
+    assert_eq!(kv.get::<Vec<i32>>("cache", "a").unwrap(), Some(vec![1, 2, 3]));
+    assert_eq!(kv.get::<String>("cache", "b").unwrap(), None);
+    assert_eq!(kv.list::<Vec<i32>>("cache").unwrap().len(), 1);
+    assert_eq!(kv.purge_expired().unwrap(), 1);
+    kv.delete("cache", "a").unwrap();
+    assert_eq!(kv.get::<Vec<i32>>("cache", "a").unwrap(), None);
+
+    kv.set("state", "x", &1, None).unwrap();
+    drop(storage);
+    // 重新打开时不重复执行迁移，数据还在
+    let storage = Storage::open(&path).unwrap();
+    assert_eq!(storage.kv().get::<i32>("state", "x").unwrap(), Some(1));
+    let mode: String = storage.conn().query_row("PRAGMA journal_mode", [], |r| r.get(0)).unwrap();
+    assert_eq!(mode, "wal");
+    storage.flush().unwrap();
+    // checkpoint(TRUNCATE) 之后 -wal 文件被清空
+    assert_eq!(fs::metadata(path.with_extension("db-wal")).unwrap().len(), 0);
+}
+
+#[tokio::test]
+async fn test_storage_call() {
+    let storage = Storage::open(Storage::MEMORY).unwrap();
+    let clone = storage.clone();
+    storage.call(|s| s.kv().set("state", "x", &1, None)).await.unwrap();
+    // clone 共用同一个连接
+    assert_eq!(clone.call(|s| s.kv().get::<i32>("state", "x")).await.unwrap(), Some(1));
+}
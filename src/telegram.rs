@@ -0,0 +1,290 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use log::error;
+use regex::Regex;
+use serde_json::{Value, json};
+use tokio::sync::watch;
+
+use crate::adapter::{ChatAdapter, Incoming, Reply};
+use crate::config::TelegramConfig;
+use crate::error::Error;
+use crate::proxy::Message;
+use crate::upstream;
+
+// sendMessage 单条消息的长度上限（字符）
+const MAX_TEXT: usize = 4096;
+// connect 失败后的重试间隔，每次翻倍
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Telegram Bot API 的接入：getUpdates 长轮询，sendMessage 回复原消息
+pub struct TelegramAdapter {
+    config: TelegramConfig,
+    client: reqwest::Client,
+    username: String,
+    mention: Regex,
+    offset: AtomicI64,
+}
+
+impl TelegramAdapter {
+    /// 通过 getMe 获取机器人的用户名，用于识别群里的 @；client 一般为 BotState::http()
+    pub async fn connect(config: TelegramConfig, client: reqwest::Client) -> Result<Self> {
+        let mut adapter = TelegramAdapter {
+            config,
+            client,
+            username: String::new(),
+            mention: Regex::new("$^")?,
+            offset: AtomicI64::new(0),
+        };
+        let me = adapter.call("getMe", json!({}), Duration::from_secs(10)).await?;
+        let username = me["username"]
+            .as_str()
+            .ok_or(Error::ResultError("getMe returned no username"))?
+            .to_string();
+        adapter.mention = Regex::new(&format!(r"(?i)@{}\b", regex::escape(&username)))?;
+        adapter.username = username;
+        Ok(adapter)
+    }
+
+    /// 启动时网络或者 Telegram 不可用也不放弃，按指数退避重试 connect，stop 变为 true 时返回 None
+    pub async fn connect_with_retry(
+        config: TelegramConfig,
+        client: reqwest::Client,
+        stop: &mut watch::Receiver<bool>,
+    ) -> Option<Self> {
+        let mut delay = RETRY_DELAY;
+        loop {
+            match TelegramAdapter::connect(config.clone(), client.clone()).await {
+                Ok(adapter) => return Some(adapter),
+                Err(e) => error!("failed to connect to telegram, retry in {}s, err: {:?}", delay.as_secs(), e),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = stop.wait_for(|s| *s) => return None,
+            }
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    async fn call(&self, method: &str, body: Value, timeout: Duration) -> Result<Value> {
+        let url = format!("{}/bot{}/{}", self.config.api.trim_end_matches('/'), self.config.token, method);
+        // url 中带有 token，出错时由 upstream 去掉
+        let resp = upstream::json(self.client.post(url).timeout(timeout).json(&body)).await?;
+        if resp["ok"] != true {
+            let description = resp["description"].as_str().unwrap_or_default();
+            Err(Error::JsonError(format!("{method} failed: {description}")))?
+        }
+        Ok(resp["result"].clone())
+    }
+
+    fn parse(&self, update: &Value) -> Option<Incoming> {
+        let msg = update.get("message")?;
+        let text = msg["text"].as_str()?;
+        let from = &msg["from"];
+        if from["is_bot"] == true {
+            return None;
+        }
+        let is_room = msg["chat"]["type"] != "private";
+        // 指令可以写成 /cmd@bot，发给其它机器人的指令直接忽略
+        let command = text.strip_prefix('/').map(|c| c.split_whitespace().next().unwrap_or_default());
+        if let Some((_, bot)) = command.and_then(|c| c.split_once('@'))
+            && !bot.eq_ignore_ascii_case(&self.username)
+        {
+            return None;
+        }
+        // 群里的指令默认是发给机器人的，或者 @ 了机器人、回复了机器人的消息
+        let replied_to_bot = msg["reply_to_message"]["from"]["username"]
+            .as_str()
+            .is_some_and(|u| u.eq_ignore_ascii_case(&self.username));
+        let mentioned = command.is_some() || self.mention.is_match(text) || replied_to_bot;
+        let content = self.mention.replace_all(text, "").trim().to_string();
+        let chat_id = msg["chat"]["id"].as_i64()?;
+        let message_id = msg["message_id"].as_i64()?;
+        Some(Incoming {
+            message: Message {
+                is_room,
+                is_memtioned: mentioned,
+                room_id: chat_id.to_string(),
+                content,
+                sender_id: from["id"].as_i64()?.to_string(),
+                message_id: format!("tg-{chat_id}-{message_id}"),
+            },
+            reply_to: message_id.to_string(),
+        })
+    }
+}
+
+fn chunks(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    chars.chunks(MAX_TEXT).map(|c| c.iter().collect()).collect()
+}
+
+#[async_trait::async_trait]
+impl ChatAdapter for TelegramAdapter {
+    fn name(&self) -> &str {
+        "telegram"
+    }
+
+    async fn poll(&self) -> Result<Vec<Incoming>> {
+        let body = json!({
+            "offset": self.offset.load(Ordering::SeqCst),
+            "timeout": self.config.poll_timeout,
+            "allowed_updates": ["message"],
+        });
+        let timeout = Duration::from_secs(self.config.poll_timeout + 10);
+        let updates = self.call("getUpdates", body, timeout).await?;
+        let updates = updates.as_array().map(Vec::as_slice).unwrap_or_default();
+        let mut messages = Vec::new();
+        for update in updates {
+            if let Some(id) = update["update_id"].as_i64() {
+                self.offset.fetch_max(id + 1, Ordering::SeqCst);
+            }
+            messages.extend(self.parse(update));
+        }
+        Ok(messages)
+    }
+
+    async fn send(&self, incoming: &Incoming, reply: &Reply) -> Result<()> {
+        let (text, quiet) = match reply {
+            Reply::None => return Ok(()),
+            Reply::Text(text) => (text, false),
+            Reply::Notice(text) | Reply::Error(text) => (text, true),
+        };
+        for chunk in chunks(text) {
+            let body = json!({
+                "chat_id": incoming.message.room_id,
+                "text": chunk,
+                "disable_notification": quiet,
+                "reply_parameters": {
+                    "message_id": incoming.reply_to.parse::<i64>().unwrap_or_default(),
+                    "allow_sending_without_reply": true,
+                },
+            });
+            self.call("sendMessage", body, Duration::from_secs(10)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_telegram() {
+    use std::sync::{Arc, Mutex};
+
+    use axum::extract::State;
+    use axum::routing::post;
+    use axum::{Json, Router};
+
+    use crate::config::Config;
+    use crate::dispatch::Dispatcher;
+    use crate::handler::HandlerMgr;
+    use crate::state::BotState;
+
+    #[derive(Clone, Default)]
+    struct Mock {
+        updates: Arc<Mutex<Vec<Value>>>,
+        sent: Arc<Mutex<Vec<Value>>>,
+        get_me: Arc<Mutex<u32>>,
+    }
+    // 第一次 getMe 失败，验证启动时会重试
+    async fn get_me(State(mock): State<Mock>) -> Json<Value> {
+        let mut calls = mock.get_me.lock().unwrap();
+        *calls += 1;
+        if *calls == 1 {
+            return Json(json!({"ok": false, "description": "try later"}));
+        }
+        Json(json!({"ok": true, "result": {"id": 1, "is_bot": true, "username": "TestBot"}}))
+    }
+    async fn get_updates(State(mock): State<Mock>, Json(body): Json<Value>) -> Json<Value> {
+        let updates: Vec<Value> = mock.updates.lock().unwrap().drain(..).collect();
+        if updates.is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(body["offset"].is_i64());
+        Json(json!({"ok": true, "result": updates}))
+    }
+    async fn send_message(State(mock): State<Mock>, Json(body): Json<Value>) -> Json<Value> {
+        mock.sent.lock().unwrap().push(body);
+        Json(json!({"ok": true, "result": {"message_id": 100}}))
+    }
+
+    let message = |update_id: i64, chat: Value, from: Value, text: &str| {
+        json!({"update_id": update_id, "message": {"message_id": update_id * 10, "chat": chat, "from": from, "text": text}})
+    };
+    let group = json!({"id": -100, "type": "supergroup"});
+    let private = json!({"id": 42, "type": "private"});
+    let alice = json!({"id": 42, "is_bot": false});
+    let mock = Mock::default();
+    *mock.updates.lock().unwrap() = vec![
+        message(1, group.clone(), alice.clone(), "/help@testbot"),
+        message(2, group.clone(), alice.clone(), "大家好"),
+        message(3, private, alice, "/nope"),
+        message(4, group.clone(), json!({"id": 7, "is_bot": true}), "/help"),
+        message(5, group, json!({"id": 43, "is_bot": false}), "/help@OtherBot"),
+    ];
+    let app = Router::new()
+        .route("/bot123:T0KEN/getMe", post(get_me))
+        .route("/bot123:T0KEN/getUpdates", post(get_updates))
+        .route("/bot123:T0KEN/sendMessage", post(send_message))
+        .with_state(mock.clone());
+    let addr = crate::testing::serve(app).await;
+
+    let config = TelegramConfig {
+        token: String::from("123:T0KEN"),
+        api: format!("http://{addr}/"),
+        poll_timeout: 1,
+    };
+    let (_stop, mut never) = tokio::sync::watch::channel(false);
+    let adapter = TelegramAdapter::connect_with_retry(config, reqwest::Client::new(), &mut never).await.unwrap();
+    assert_eq!(*mock.get_me.lock().unwrap(), 2);
+    let in_group = |text: &str| adapter.parse(&message(9, json!({"id": 1, "type": "group"}), json!({"id": 2}), text));
+    let parsed = in_group("@TestBot 你好").unwrap();
+    assert!(parsed.message.is_room && parsed.message.is_memtioned);
+    assert_eq!(parsed.message.content, "你好");
+    assert!(in_group("/help@testbot 1").unwrap().message.is_memtioned);
+    assert!(in_group("/help@OtherBot").is_none());
+    assert!(!in_group("看看 /help@OtherBot").unwrap().message.is_memtioned);
+    // 出错时错误中不带 token
+    let e = adapter.call("nope", json!({}), Duration::from_secs(1)).await.unwrap_err();
+    match e.downcast_ref::<Error>() {
+        Some(Error::HttpError { status, url, .. }) => {
+            assert_eq!(status.as_u16(), 404);
+            assert_eq!(url, &format!("http://{addr}/bot***/nope"));
+        }
+        e => panic!("unexpected {e:?}"),
+    }
+    assert!(!format!("{e:?}").contains("T0KEN"));
+
+    let config = Config::builder()
+        .set("rooms", r#"[{"id": "-100"}]"#)
+        .set("users", r#"[{"id": "42"}]"#)
+        .build()
+        .unwrap();
+    let mut handlers = HandlerMgr::new();
+    handlers.register_handler(crate::help::Help::new());
+    let dispatcher = Arc::new(Dispatcher::new(Arc::new(BotState::new(config).unwrap()), handlers, 4));
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    let task = tokio::spawn(crate::adapter::run(Arc::new(adapter), dispatcher, stopped));
+
+    for _ in 0..100 {
+        if mock.sent.lock().unwrap().len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    stop.send(true).unwrap();
+    task.await.unwrap();
+
+    let mut sent = mock.sent.lock().unwrap().clone();
+    sent.sort_by_key(|s| s["chat_id"].as_str().unwrap().to_string());
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0]["chat_id"], "-100");
+    assert!(sent[0]["text"].as_str().unwrap().starts_with("可用指令列表"));
+    assert_eq!(sent[0]["reply_parameters"]["message_id"], 10);
+    assert_eq!(sent[0]["disable_notification"], false);
+    assert_eq!(sent[1]["chat_id"], "42");
+    assert_eq!(sent[1]["text"], "没有这个指令，发送 /help 看看");
+    assert_eq!(sent[1]["disable_notification"], true);
+    assert_eq!(chunks(&"字".repeat(MAX_TEXT + 1)).len(), 2);
+}
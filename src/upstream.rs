@@ -0,0 +1,229 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::{Proxy, RequestBuilder, Response, Url};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::error::{Error, strip_query};
+
+// 可以在 proxy_providers 中使用的名字和对应的地址
+fn provider_urls(config: &Config) -> Vec<(&'static str, &str)> {
+    let upstream = &config.upstream;
+    let mut urls = vec![
+        ("k780", upstream.k780.as_str()),
+        ("okx", upstream.okx.as_str()),
+        ("binance", upstream.binance.as_str()),
+        ("tanshu", upstream.tanshu.as_str()),
+        ("juhe", upstream.juhe.as_str()),
+        ("aceodds", upstream.aceodds.as_str()),
+        ("gpt", config.gpt_api.as_str()),
+    ];
+    urls.extend(config.telegram.as_ref().map(|t| ("telegram", t.api.as_str())));
+    urls.extend(config.matrix.as_ref().map(|m| ("matrix", m.homeserver.as_str())));
+    urls
+}
+
+fn is_local(host: &str) -> bool {
+    host == "localhost"
+        || host
+            .trim_matches(['[', ']'])
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+// 本机地址（例如录制和回放用的转发服务）不走代理
+fn proxy(config: &Config, proxy: &str) -> Result<Proxy> {
+    let proxy = Url::parse(proxy).map_err(|e| Error::ConfigError(format!("invalid upstream.proxy {proxy}: {e}")))?;
+    let mut hosts = HashSet::new();
+    for name in &config.upstream.proxy_providers {
+        let (_, url) = provider_urls(config)
+            .into_iter()
+            .find(|(provider, _)| provider == name)
+            .ok_or_else(|| Error::ConfigError(format!("unknown provider {name} in upstream.proxy_providers")))?;
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_string))
+            .ok_or_else(|| Error::ConfigError(format!("invalid url {url} for provider {name}")))?;
+        hosts.insert(host);
+    }
+    Ok(Proxy::custom(move |url| {
+        let host = url.host_str()?;
+        if is_local(host) || !(hosts.is_empty() || hosts.contains(host)) {
+            return None;
+        }
+        Some(proxy.clone())
+    }))
+}
+
+/// 创建所有模块共用的 reqwest::Client：代理、UA、连接时限，以及默认的请求时限
+///
+/// 各个上游的请求时限在请求时按 upstream.timeout(provider) 设置，会覆盖这里的默认值
+pub fn build_client(config: &Config) -> Result<reqwest::Client> {
+    let upstream = &config.upstream;
+    let mut builder = reqwest::Client::builder()
+        .user_agent(&upstream.user_agent)
+        .connect_timeout(Duration::from_secs(upstream.connect_timeout))
+        .timeout(Duration::from_secs(upstream.timeout));
+    if let Some(url) = upstream.proxy.as_deref().filter(|p| !p.is_empty()) {
+        builder = builder.proxy(proxy(config, url)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// 发送请求，连接失败或超时为 TransportError，非 2xx 为 HttpError（带上响应内容）
+pub async fn send(req: RequestBuilder) -> Result<Response> {
+    let resp = req.send().await.map_err(Error::from_reqwest)?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(Error::HttpError {
+            status,
+            url: strip_query(resp.url().as_str()),
+            response: resp.text().await.unwrap_or_default(),
+        }
+        .into());
+    }
+    Ok(resp)
+}
+
+/// send 之后按 json 解析，解析失败为 DecodeError
+pub async fn json(req: RequestBuilder) -> Result<Value> {
+    let resp = send(req).await?;
+    // 读取响应体时的错误不带 url，这里补上
+    let url = resp.url().clone();
+    let body = resp.bytes().await.map_err(|e| Error::from_reqwest(e.with_url(url.clone())))?;
+    let value = serde_json::from_slice(&body).map_err(|e| Error::DecodeError {
+        url: strip_query(url.as_str()),
+        message: e.to_string(),
+    })?;
+    Ok(value)
+}
+
+/// send 之后读取文本
+pub async fn text(req: RequestBuilder) -> Result<String> {
+    let resp = send(req).await?;
+    let url = resp.url().clone();
+    Ok(resp.text().await.map_err(|e| Error::from_reqwest(e.with_url(url)))?)
+}
+
+/// 所有上游都指向一个连不上的地址，测试出错的情况
+#[cfg(test)]
+pub(crate) fn unreachable_config() -> Config {
+    let mut config = Config::default();
+    let upstream = &mut config.upstream;
+    for url in [
+        &mut upstream.k780,
+        &mut upstream.okx,
+        &mut upstream.binance,
+        &mut upstream.tanshu,
+        &mut upstream.juhe,
+        &mut upstream.aceodds,
+        &mut config.gpt_api,
+    ] {
+        *url = String::from("http://127.0.0.1:1");
+    }
+    config
+}
+
+#[cfg(test)]
+pub(crate) fn is_transport_error(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<Error>(), Some(Error::TransportError { .. }))
+}
+
+#[tokio::test]
+async fn test_request_errors() {
+    use axum::Router;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+
+    let app = Router::new()
+        .route("/ok", get(|| async { r#"{"ok": true}"# }))
+        .route("/garbage", get(|| async { "<html>" }))
+        .route("/boom", get(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "boom") }))
+        .route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                "late"
+            }),
+        );
+    let addr = crate::testing::serve(app).await;
+
+    let client = reqwest::Client::new();
+    let err = |e: anyhow::Error| e.downcast::<Error>().unwrap();
+    assert_eq!(json(client.get(format!("http://{addr}/ok"))).await.unwrap()["ok"], true);
+    match err(json(client.get(format!("http://{addr}/garbage?key=s3cret"))).await.unwrap_err()) {
+        Error::DecodeError { url, .. } => assert_eq!(url, format!("http://{addr}/garbage")),
+        e => panic!("unexpected {e:?}"),
+    }
+    match err(text(client.get(format!("http://{addr}/boom"))).await.unwrap_err()) {
+        Error::HttpError { status, response, .. } => {
+            assert_eq!(status.as_u16(), 500);
+            assert_eq!(response, "boom");
+        }
+        e => panic!("unexpected {e:?}"),
+    }
+    let slow = client.get(format!("http://{addr}/slow")).timeout(Duration::from_millis(100));
+    assert!(matches!(err(text(slow).await.unwrap_err()), Error::TransportError { .. }));
+    match err(send(client.get("http://127.0.0.1:1/x?appkey=s3cret")).await.unwrap_err()) {
+        Error::TransportError { url, message } => {
+            assert_eq!(url, "http://127.0.0.1:1/x");
+            assert!(!message.contains("s3cret"));
+        }
+        e => panic!("unexpected {e:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_build_client() {
+    use std::sync::{Arc, Mutex};
+
+    use axum::Router;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, Uri};
+
+    // 作为 http 代理时收到的是完整的 url
+    type Seen = Arc<Mutex<Vec<(String, String)>>>;
+    async fn forward(State(seen): State<Seen>, uri: Uri, headers: HeaderMap) -> &'static str {
+        let agent = headers["user-agent"].to_str().unwrap().to_string();
+        seen.lock().unwrap().push((uri.to_string(), agent));
+        "proxied"
+    }
+    let seen = Seen::default();
+    let app = Router::new().fallback(forward).with_state(seen.clone());
+    let addr = crate::testing::serve(app).await;
+
+    let config = Config::builder()
+        .set("upstream.proxy", format!("http://{addr}"))
+        .set("upstream.proxy_providers", r#"["okx"]"#)
+        .set("upstream.okx", "http://okx.invalid")
+        .set("upstream.k780", "http://k780.invalid")
+        .set("upstream.user_agent", "test-agent")
+        .build()
+        .unwrap();
+    let client = build_client(&config).unwrap();
+    let body = client.get("http://okx.invalid/api?x=1").send().await.unwrap().text().await.unwrap();
+    assert_eq!(body, "proxied");
+    // 不在 proxy_providers 中的直接连接，本机地址也不走代理
+    assert!(client.get("http://k780.invalid/").send().await.is_err());
+    let direct = client.get(format!("http://{addr}/direct")).send().await.unwrap();
+    assert_eq!(direct.text().await.unwrap(), "proxied");
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            (String::from("http://okx.invalid/api?x=1"), String::from("test-agent")),
+            (String::from("/direct"), String::from("test-agent")),
+        ]
+    );
+
+    assert_eq!(config.upstream.timeout("gpt"), Duration::from_secs(60));
+    assert_eq!(config.upstream.timeout("okx"), Duration::from_secs(10));
+    let unknown = Config::builder()
+        .set("upstream.proxy", "http://127.0.0.1:1")
+        .set("upstream.proxy_providers", r#"["nope"]"#)
+        .build()
+        .unwrap();
+    assert!(build_client(&unknown).is_err());
+}
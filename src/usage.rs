@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::config::UsageConfig;
+use crate::gpt::Completion;
+
+/// 一次大模型调用的用量
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub at: DateTime<Local>,
+    pub room: String,
+    pub user: String,
+    pub handler: String,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// 估算费用，单位与 pricing 中配置的价格一致
+    pub cost: f64,
+}
+
+impl UsageRecord {
+    pub fn new(config: &UsageConfig, room: &str, user: &str, handler: &str, completion: &Completion) -> Self {
+        let usage = completion.usage;
+        let cost = config
+            .price(&completion.model)
+            .map(|p| {
+                (usage.prompt_tokens as f64 * p.prompt + usage.completion_tokens as f64 * p.completion)
+                    / 1_000_000.0
+            })
+            .unwrap_or(0.0);
+        UsageRecord {
+            at: Local::now(),
+            room: room.to_string(),
+            user: user.to_string(),
+            handler: handler.to_string(),
+            model: completion.model.clone(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            cost,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct UsageTotal {
+    pub calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+impl UsageTotal {
+    fn add(&mut self, r: &UsageRecord) {
+        self.calls += 1;
+        self.prompt_tokens += r.prompt_tokens;
+        self.completion_tokens += r.completion_tokens;
+        self.cost += r.cost;
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct UsageSummary {
+    pub total: UsageTotal,
+    pub by_room: BTreeMap<String, UsageTotal>,
+    pub by_user: BTreeMap<String, UsageTotal>,
+    pub by_handler: BTreeMap<String, UsageTotal>,
+}
+
+impl UsageSummary {
+    pub fn from_records(records: &[UsageRecord]) -> Self {
+        let mut summary = UsageSummary::default();
+        for r in records {
+            summary.total.add(r);
+            summary.by_room.entry(r.room.clone()).or_default().add(r);
+            summary.by_user.entry(r.user.clone()).or_default().add(r);
+            summary.by_handler.entry(r.handler.clone()).or_default().add(r);
+        }
+        summary
+    }
+}
+
+fn start_of(date: NaiveDate) -> DateTime<Local> {
+    Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        .unwrap_or_else(Local::now)
+}
+
+pub fn day_range(date: NaiveDate) -> (DateTime<Local>, DateTime<Local>) {
+    (start_of(date), start_of(date + chrono::Duration::days(1)))
+}
+
+pub fn month_range(date: NaiveDate) -> (DateTime<Local>, DateTime<Local>) {
+    let first = date.with_day(1).unwrap();
+    let next = if first.month() == 12 {
+        NaiveDate::from_ymd_opt(first.year() + 1, 1, 1).unwrap()
+    } else {
+        NaiveDate::from_ymd_opt(first.year(), first.month() + 1, 1).unwrap()
+    };
+    (start_of(first), start_of(next))
+}
+
+#[test]
+fn test_usage_summary() {
+    use crate::config::ModelPrice;
+    use crate::gpt::Usage;
+    use crate::storage::Storage;
+
+    let mut config = UsageConfig::default();
+    config.pricing.insert(
+        String::from("gpt-4o-mini"),
+        ModelPrice { prompt: 0.15, completion: 0.6 },
+    );
+    let completion = Completion {
+        content: String::from("hi"),
+        model: String::from("gpt-4o-mini"),
+        usage: Usage { prompt_tokens: 1_000_000, completion_tokens: 500_000, total_tokens: 1_500_000 },
+        tool_calls: Vec::new(),
+    };
+
+    let storage = Storage::memory().unwrap();
+    let usage = storage.usage();
+    usage.insert(&UsageRecord::new(&config, "room", "a", "gpt", &completion)).unwrap();
+    usage.insert(&UsageRecord::new(&config, "room", "b", "gamble", &completion)).unwrap();
+    // 带日期后缀的模型名按前缀匹配价格
+    let mut dated = completion.clone();
+    dated.model = String::from("gpt-4o-mini-2024-07-18");
+    assert!((UsageRecord::new(&config, "room", "a", "gpt", &dated).cost - 0.45).abs() < 1e-9);
+    let mut unknown = completion.clone();
+    unknown.model = String::from("unknown");
+    usage.insert(&UsageRecord::new(&config, "other", "a", "gpt", &unknown)).unwrap();
+
+    let (from, to) = day_range(Local::now().date_naive());
+    let summary = usage.summary(from, to).unwrap();
+    assert_eq!(summary.total.calls, 3);
+    assert!((summary.total.cost - 0.9).abs() < 1e-9);
+    assert_eq!(summary.by_user["a"].calls, 2);
+    assert_eq!(summary.by_handler["gamble"].prompt_tokens, 1_000_000);
+    let (from, to) = month_range(Local::now().date_naive());
+    assert!((usage.cost(from, to).unwrap() - 0.9).abs() < 1e-9);
+
+    let (from, to) = month_range(NaiveDate::from_ymd_opt(2024, 12, 15).unwrap());
+    assert_eq!(from.date_naive(), NaiveDate::from_ymd_opt(2024, 12, 1).unwrap());
+    assert_eq!(to.date_naive(), NaiveDate::from_ymd_opt(2025, 1, 1).unwrap());
+}
@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::WebhookConfig;
+use crate::upstream;
+
+/// 推送给桥接端的消息，字段名和 proto 的 json 形式一致
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PushMessage {
+    pub is_room: bool,
+    pub room_id: String,
+    pub content: String,
+}
+
+pub const SIGNATURE_HEADER: &str = "X-Bot-Signature";
+
+fn mac(secret: &[u8], body: &[u8]) -> Hmac<Sha256> {
+    // HMAC 接受任意长度的密钥
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(body);
+    mac
+}
+
+/// X-Bot-Signature 的值：sha256= 加上 HMAC-SHA256 的十六进制
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    format!("sha256={}", hex::encode(mac(secret, body).finalize().into_bytes()))
+}
+
+/// 以常数时间校验 X-Bot-Signature，用于接收端
+pub fn verify(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let Some(digest) = signature.strip_prefix("sha256=").and_then(|h| hex::decode(h).ok()) else {
+        return false;
+    };
+    mac(secret, body).verify_slice(&digest).is_ok()
+}
+
+/// 把消息 POST 到 webhook.urls 中的每个地址
+pub struct Webhook {
+    config: WebhookConfig,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    /// client 一般为 BotState::http()
+    pub fn new(config: WebhookConfig, client: reqwest::Client) -> Self {
+        Webhook { config, client }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config.urls.is_empty()
+    }
+
+    async fn post(&self, url: &str, body: &str) -> Result<()> {
+        let mut req = self
+            .client
+            .post(url)
+            .timeout(Duration::from_secs(self.config.timeout))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        if let Some(secret) = &self.config.secret {
+            req = req.header(SIGNATURE_HEADER, sign(secret.as_bytes(), body.as_bytes()));
+        }
+        upstream::send(req).await?;
+        Ok(())
+    }
+
+    /// 依次推送到每个地址，失败的按次数重试；返回成功的地址数，全部失败时返回最后一个错误
+    pub async fn push(&self, message: &PushMessage) -> Result<usize> {
+        let body = serde_json::to_string(message)?;
+        let mut delivered = 0;
+        let mut last_error = None;
+        for url in &self.config.urls {
+            let mut attempt = 0;
+            loop {
+                match self.post(url, &body).await {
+                    Ok(()) => {
+                        delivered += 1;
+                        break;
+                    }
+                    Err(e) if attempt < self.config.retries => {
+                        attempt += 1;
+                        info!("webhook {} failed, retry {}, err: {:?}", url, attempt, e);
+                        tokio::time::sleep(Duration::from_millis(200 * u64::from(attempt))).await;
+                    }
+                    Err(e) => {
+                        error!("webhook {} failed, err: {:?}", url, e);
+                        last_error = Some(e);
+                        break;
+                    }
+                }
+            }
+        }
+        match last_error {
+            Some(e) if delivered == 0 => Err(e),
+            _ => Ok(delivered),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_webhook() {
+    use std::sync::{Arc, Mutex};
+
+    use axum::Router;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+
+    // RFC 4231 test case 2
+    assert_eq!(
+        sign(b"Jefe", b"what do ya want for nothing?"),
+        "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+
+    // 第一次返回 500，之后记录收到的消息
+    type Received = Arc<Mutex<Vec<(String, String)>>>;
+    async fn hook(State(received): State<Received>, headers: HeaderMap, body: String) -> StatusCode {
+        let mut received = received.lock().unwrap();
+        let signature = headers[SIGNATURE_HEADER].to_str().unwrap().to_string();
+        received.push((signature, body));
+        if received.len() == 1 { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::NO_CONTENT }
+    }
+    let received = Received::default();
+    let app = Router::new().route("/hook", post(hook)).with_state(received.clone());
+    let addr = crate::testing::serve(app).await;
+
+    let message = PushMessage {
+        is_room: true,
+        room_id: String::from("room"),
+        content: String::from("开盘了"),
+    };
+    let client = reqwest::Client::new();
+    let webhook = Webhook::new(
+        WebhookConfig {
+            urls: vec![format!("http://{addr}/hook")],
+            secret: Some(String::from("s3cret")),
+            ..Default::default()
+        },
+        client.clone(),
+    );
+    assert_eq!(webhook.push(&message).await.unwrap(), 1);
+    let received = received.lock().unwrap().clone();
+    assert_eq!(received.len(), 2);
+    let (signature, body) = &received[1];
+    assert!(verify(b"s3cret", body.as_bytes(), signature));
+    assert!(!verify(b"other", body.as_bytes(), signature));
+    assert!(!verify(b"s3cret", body.as_bytes(), "sha256=zz"));
+    assert_eq!(serde_json::from_str::<PushMessage>(body).unwrap(), message);
+    assert_eq!(body, r#"{"isRoom":true,"roomId":"room","content":"开盘了"}"#);
+
+    let broken = Webhook::new(
+        WebhookConfig {
+            urls: vec![String::from("http://127.0.0.1:1/hook")],
+            retries: 0,
+            ..Default::default()
+        },
+        client,
+    );
+    let e = broken.push(&message).await.unwrap_err();
+    assert!(upstream::is_transport_error(&e), "{e:?}");
+}